@@ -1,137 +1,399 @@
+use crate::amount::Amount;
 use rust_decimal::Decimal;
 use serde::Serialize;
-use serde::Serializer;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug)]
 pub enum AccountError {
     AccountLocked,
+    AccountFrozen,
     InsufficientFunds,
     TransactionAlreadyDisputed,
     TransactionNotDisputed,
+    TransactionFinalized,
+    InsufficientFundsForDispute,
+    DisputeExceedsTransaction,
+    AmountOverflow,
+    BalanceInvariantViolated,
 }
 
 impl AccountError {
     pub fn as_str(&self) -> &'static str {
         match self {
             AccountError::AccountLocked => "Account is locked",
+            AccountError::AccountFrozen => "Account is frozen",
             AccountError::InsufficientFunds => "Insufficient funds",
             AccountError::TransactionAlreadyDisputed => "Transaction already disputed",
             AccountError::TransactionNotDisputed => "Transaction not disputed",
+            AccountError::TransactionFinalized => "Transaction already finalized",
+            AccountError::InsufficientFundsForDispute => "Insufficient available funds to hold the disputed amount",
+            AccountError::DisputeExceedsTransaction => "Disputed portions exceed the transaction amount",
+            AccountError::AmountOverflow => "Balance out of the range of the amount type",
+            AccountError::BalanceInvariantViolated => "Available and held funds do not add up to the total",
         }
     }
 }
 
-// Serialize Decimal with rounding to 4 decimal places
-fn serialize_rounded<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_str(&value.round_dp(4).to_string())
+// Outcome of a resolve or chargeback that didn't fail. Resolving or charging back a resolved transaction again,
+// e.g. when replaying an input, is a no-op the engine may log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Settlement {
+    Applied,
+    AlreadyResolved,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Account {
+// Balances of an account as written in the output, rounded to the output precision unless written in minor units
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountRecord {
     pub client: u16,
-    #[serde(serialize_with = "serialize_rounded")]
     pub available: Decimal,
-    #[serde(serialize_with = "serialize_rounded")]
     pub held: Decimal,
-    #[serde(serialize_with = "serialize_rounded")]
     pub total: Decimal,
     pub locked: bool,
-    #[serde(skip)]
-    pub disputed_transactions: HashMap<u32, Decimal>,
 }
 
-impl Account {
+impl AccountRecord {
+    pub const HEADERS: [&'static str; 5] = ["client", "available", "held", "total", "locked"];
+
+    // A locked account is still worth reporting with a zero balance
+    pub fn is_zero_balance(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero() && self.total.is_zero() && !self.locked
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account<A = Decimal> {
+    pub client: u16,
+    pub available: A,
+    pub held: A,
+    pub total: A,
+    pub locked: bool,
+    // Set by an administrative freeze, unlike locked it can be lifted and doesn't come with a chargeback
+    pub frozen: bool,
+    // Currency of the first transaction applied to the account with one, if any
+    pub currency: Option<String>,
+    pub disputed_transactions: HashMap<u32, A>,
+    // Disputed amount of each disputed transaction, its portions added up. Above the held amount when the hold
+    // was capped to the available funds.
+    pub disputed_amounts: HashMap<u32, A>,
+    pub resolved_transactions: HashSet<u32>,
+    // Charged back transactions, which can't be disputed or resolved anymore
+    pub finalized_transactions: HashSet<u32>,
+    // Disputed transactions which are withdrawals, see settle for how their funds move
+    pub disputed_withdrawals: HashSet<u32>,
+    // Withdrawals in a row rejected for insufficient funds, reset by a successful one
+    pub failed_withdrawals: u32,
+}
+
+impl<A: Amount> Account<A> {
     pub fn new(client: u16) -> Self {
         Self {
             client,
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
-            total: Decimal::ZERO,
+            available: A::ZERO,
+            held: A::ZERO,
+            total: A::ZERO,
             locked: false,
+            frozen: false,
+            currency: None,
             disputed_transactions: HashMap::new(),
+            disputed_amounts: HashMap::new(),
+            resolved_transactions: HashSet::new(),
+            finalized_transactions: HashSet::new(),
+            disputed_withdrawals: HashSet::new(),
+            failed_withdrawals: 0,
         }
     }
 
-    pub fn deposit(&mut self, amount: Decimal) -> Result<(), AccountError> {
+    // Held amount of each currently disputed transaction
+    pub fn disputed(&self) -> impl Iterator<Item = (u32, A)> + '_ {
+        self.disputed_transactions.iter().map(|(tx_id, amount)| (*tx_id, *amount))
+    }
+
+    pub fn deposit(&mut self, amount: A) -> Result<(), AccountError> {
         if self.locked {
             return Err(AccountError::AccountLocked);
         }
+
+        if self.frozen {
+            return Err(AccountError::AccountFrozen);
+        }
         
-        self.available += amount;
-        self.total += amount;
+        let available = add(self.available, amount)?;
+        self.total = add(self.total, amount)?;
+        self.available = available;
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), AccountError> {
+    pub fn withdraw(&mut self, amount: A) -> Result<(), AccountError> {
+        self.withdraw_with_overdraft(amount, A::ZERO)
+    }
+
+    // Withdraw allowing the available balance to go negative, down to -overdraft_limit
+    pub fn withdraw_with_overdraft(&mut self, amount: A, overdraft_limit: A) -> Result<(), AccountError> {
         if self.locked {
             return Err(AccountError::AccountLocked);
         }
+
+        if self.frozen {
+            return Err(AccountError::AccountFrozen);
+        }
         
-        if self.available < amount {
+        if add(self.available, overdraft_limit)? < amount {
+            self.failed_withdrawals += 1;
             return Err(AccountError::InsufficientFunds);
         }
         
-        self.available -= amount;
-        self.total -= amount;
+        let available = sub(self.available, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.available = available;
+        self.failed_withdrawals = 0;
         Ok(())
     }
 
-    pub fn dispute(&mut self, mut amount: Decimal, tx_id: u32) -> Result<(), AccountError> {
+    // Hold the disputed amount of a deposit, returning the amount actually held: only what is left of the available
+    // funds when less than the disputed amount
+    pub fn dispute(&mut self, amount: A, tx_id: u32) -> Result<A, AccountError> {
+        self.dispute_with_partial_hold(amount, tx_id, true)
+    }
+
+    // Without partial hold, a dispute of more than the available funds is rejected instead of holding what is left
+    pub fn dispute_with_partial_hold(&mut self, amount: A, tx_id: u32, partial_hold: bool) -> Result<A, AccountError> {
+        self.hold(amount, tx_id, partial_hold, false)
+    }
+
+    // Dispute a portion of a transaction, adding to what previous disputes of portions of it already hold.
+    // The portions can't add up to more than the original amount of the transaction.
+    pub fn dispute_portion(&mut self, amount: A, original_amount: A, tx_id: u32, partial_hold: bool)
+        -> Result<A, AccountError> {
+        self.check_portion(amount, original_amount, tx_id)?;
+        self.hold(amount, tx_id, partial_hold, true)
+    }
+
+    pub fn dispute_withdrawal_portion(&mut self, amount: A, original_amount: A, tx_id: u32) -> Result<(), AccountError> {
+        self.check_portion(amount, original_amount, tx_id)?;
+        self.dispute_withdrawal(amount, tx_id, true)
+    }
+
+    // Amount disputed so far of a transaction, zero if it isn't disputed
+    pub fn disputed_amount(&self, tx_id: u32) -> A {
+        self.disputed_amounts.get(&tx_id).copied().unwrap_or(A::ZERO)
+    }
+
+    fn check_portion(&self, amount: A, original_amount: A, tx_id: u32) -> Result<(), AccountError> {
+        if add(self.disputed_amount(tx_id), amount)? > original_amount {
+            return Err(AccountError::DisputeExceedsTransaction);
+        }
+        Ok(())
+    }
+
+    // Dispute a withdrawal, or a portion of it: the withdrawn funds are clawed back into held
+    pub fn dispute_withdrawal(&mut self, amount: A, tx_id: u32, add_to_dispute: bool) -> Result<(), AccountError> {
+        self.check_disputable(tx_id, add_to_dispute)?;
+
+        let held = add(self.held, amount)?;
+        let total = add(self.total, amount)?;
+        let disputed = add(self.disputed_transactions.get(&tx_id).copied().unwrap_or(A::ZERO), amount)?;
+        let disputed_amount = add(self.disputed_amount(tx_id), amount)?;
+        self.held = held;
+        self.total = total;
+        self.disputed_transactions.insert(tx_id, disputed);
+        self.disputed_amounts.insert(tx_id, disputed_amount);
+        self.disputed_withdrawals.insert(tx_id);
+        self.resolved_transactions.remove(&tx_id);
+        Ok(())
+    }
+
+    fn check_disputable(&self, tx_id: u32, add_to_dispute: bool) -> Result<(), AccountError> {
+        if self.finalized_transactions.contains(&tx_id) {
+            return Err(AccountError::TransactionFinalized);
+        }
+
         if self.locked {
             return Err(AccountError::AccountLocked);
         }
+
+        if self.frozen {
+            return Err(AccountError::AccountFrozen);
+        }
         
-        if self.disputed_transactions.contains_key(&tx_id) {
+        if !add_to_dispute && self.disputed_transactions.contains_key(&tx_id) {
             return Err(AccountError::TransactionAlreadyDisputed);
         }
+        Ok(())
+    }
+
+    fn hold(&mut self, mut amount: A, tx_id: u32, partial_hold: bool, add_to_dispute: bool) -> Result<A, AccountError> {
+        self.check_disputable(tx_id, add_to_dispute)?;
         
-        // Adjust amount to available if insufficient
+        if !partial_hold && self.available < amount {
+            return Err(AccountError::InsufficientFundsForDispute);
+        }
+        let disputed_amount = add(self.disputed_amount(tx_id), amount)?;
+
+        // Adjust amount to available if insufficient (nothing can be held from an overdrawn balance)
         if self.available < amount {
-            amount = self.available;
-            eprintln!("Disputing transaction {} with not enough balance available, holding amount {} instead",
-                      tx_id, amount);
+            amount = if self.available > A::ZERO { self.available } else { A::ZERO };
         }
         
-        self.available -= amount;
-        self.held += amount;
-        self.disputed_transactions.insert(tx_id, amount);
-        Ok(())
+        let available = sub(self.available, amount)?;
+        let held = add(self.held, amount)?;
+        let disputed = add(self.disputed_transactions.get(&tx_id).copied().unwrap_or(A::ZERO), amount)?;
+        self.available = available;
+        self.held = held;
+        self.disputed_transactions.insert(tx_id, disputed);
+        self.disputed_amounts.insert(tx_id, disputed_amount);
+        self.resolved_transactions.remove(&tx_id);
+        Ok(amount)
     }
 
-    pub fn resolve(&mut self, tx_id: u32) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::AccountLocked);
+    // Allowed on a locked account, so that funds held before the lock are not trapped
+    pub fn resolve(&mut self, tx_id: u32) -> Result<Settlement, AccountError> {
+        if self.finalized_transactions.contains(&tx_id) {
+            return Err(AccountError::TransactionFinalized);
         }
-        
-        let amount = self.disputed_transactions.get(&tx_id)
+
+        if self.is_already_resolved(tx_id) {
+            return Ok(Settlement::AlreadyResolved);
+        }
+
+        let amount = *self.disputed_transactions.get(&tx_id)
             .ok_or(AccountError::TransactionNotDisputed)?;
         
-        self.held -= amount;
-        self.available += amount;
+        self.settle(tx_id, amount, false)?;
         self.disputed_transactions.remove(&tx_id);
+        self.disputed_amounts.remove(&tx_id);
+        self.resolved_transactions.insert(tx_id);
+        Ok(Settlement::Applied)
+    }
+
+    // Resolve every dispute at once, releasing the held amounts of deposits back to available
+    pub fn resolve_all(&mut self) -> Result<(), AccountError> {
+        // Settled on a copy, so that an overflow leaves every dispute open
+        let mut resolved = self.clone();
+        for (tx_id, amount) in std::mem::take(&mut resolved.disputed_transactions) {
+            resolved.settle(tx_id, amount, false)?;
+            resolved.resolved_transactions.insert(tx_id);
+        }
+        resolved.disputed_amounts.clear();
+        *self = resolved;
         Ok(())
     }
 
-    pub fn chargeback(&mut self, tx_id: u32) -> Result<(), AccountError> {
-        let amount = self.disputed_transactions.get(&tx_id)
+    // A chargeback only finalizes an open dispute, it never creates one: the amount comes from the disputed
+    // transactions, not from the transaction history. The lock is not checked on purpose, like on resolve:
+    // disputes opened before another chargeback locked the account must still be finalized.
+    pub fn chargeback(&mut self, tx_id: u32) -> Result<Settlement, AccountError> {
+        if self.finalized_transactions.contains(&tx_id) {
+            return Err(AccountError::TransactionFinalized);
+        }
+
+        if self.is_already_resolved(tx_id) {
+            return Ok(Settlement::AlreadyResolved);
+        }
+
+        let amount = *self.disputed_transactions.get(&tx_id)
             .ok_or(AccountError::TransactionNotDisputed)?;
         
-        self.held -= amount;
-        self.total -= amount;
+        self.settle(tx_id, amount, true)?;
         self.locked = true;
         self.disputed_transactions.remove(&tx_id);
+        self.disputed_amounts.remove(&tx_id);
+        self.finalized_transactions.insert(tx_id);
+        Ok(Settlement::Applied)
+    }
+
+    // Release the amount held for a dispute, on a resolve or a chargeback. The balances move as follows,
+    // for an amount a of the disputed transaction:
+    //
+    //              | deposit                  | withdrawal
+    //   dispute    | available -a, held +a    | held +a, total +a
+    //   resolve    | held -a, available +a    | held -a, total -a
+    //   chargeback | held -a, total -a        | held -a, available +a
+    //
+    // A resolved withdrawal stands, a charged back one credits the withdrawn funds back to the account
+    fn settle(&mut self, tx_id: u32, amount: A, chargeback: bool) -> Result<(), AccountError> {
+        let held = sub(self.held, amount)?;
+        if self.disputed_withdrawals.contains(&tx_id) == chargeback {
+            self.available = add(self.available, amount)?;
+        } else {
+            self.total = sub(self.total, amount)?;
+        }
+        self.held = held;
+        self.disputed_withdrawals.remove(&tx_id);
         Ok(())
     }
+
+    // Freezing blocks deposits, withdrawals and disputes, the balances are left untouched
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn unfreeze(&mut self) {
+        self.frozen = false;
+    }
+
+    // Funds the client can withdraw right now
+    pub fn free_balance(&self) -> A {
+        self.available
+    }
+
+    // Funds owned by the client, held ones included, which is the total when the invariant holds.
+    // None if they are out of the range of the amount type.
+    pub fn equity(&self) -> Option<A> {
+        self.available.checked_add(self.held)
+    }
+
+    // Every operation moves funds between available and held, or changes total along with one of them
+    pub fn check_invariant(&self) -> Result<(), AccountError> {
+        if self.equity() == Some(self.total) {
+            Ok(())
+        } else {
+            Err(AccountError::BalanceInvariantViolated)
+        }
+    }
+
+    // A resolved transaction stays resolved until it is disputed again
+    fn is_already_resolved(&self, tx_id: u32) -> bool {
+        self.resolved_transactions.contains(&tx_id) && !self.disputed_transactions.contains_key(&tx_id)
+    }
+}
+
+fn add<A: Amount>(amount: A, other: A) -> Result<A, AccountError> {
+    amount.checked_add(other).ok_or(AccountError::AmountOverflow)
+}
+
+fn sub<A: Amount>(amount: A, other: A) -> Result<A, AccountError> {
+    amount.checked_sub(other).ok_or(AccountError::AmountOverflow)
+}
+
+impl Account {
+    pub fn to_record(&self, output_precision: u32) -> AccountRecord {
+        AccountRecord {
+            client: self.client,
+            available: self.available.round_dp(output_precision),
+            held: self.held.round_dp(output_precision),
+            total: self.total.round_dp(output_precision),
+            locked: self.locked,
+        }
+    }
+
+    // Record of the balances as they are, for the outputs in minor units which must not hide a fraction of a unit
+    pub fn to_exact_record(&self) -> AccountRecord {
+        AccountRecord {
+            client: self.client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::MinorUnits;
     use std::str::FromStr;
 
     #[test]
@@ -163,12 +425,49 @@ mod tests {
         let amount = Decimal::from_str("10.0").unwrap();
         
         account.deposit(amount).unwrap();
-        assert!(account.dispute(amount, 1).is_ok());
+        assert_eq!(account.dispute(amount, 1).unwrap(), amount);
         assert_eq!(account.available, Decimal::ZERO);
         assert_eq!(account.held, amount);
         assert_eq!(account.total, amount);
     }
 
+    #[test]
+    fn test_account_dispute_after_withdrawal() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.withdraw(Decimal::from_str("4.0").unwrap()).unwrap();
+
+        // Without partial hold, balances are untouched
+        assert!(account.dispute_with_partial_hold(amount, 1, false).is_err());
+        assert_eq!(account.available, Decimal::from_str("6.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(account.disputed_transactions.is_empty());
+
+        // By default, what is left available is held
+        assert_eq!(account.dispute(amount, 1).unwrap(), Decimal::from_str("6.0").unwrap());
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::from_str("6.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("6.0").unwrap());
+    }
+
+    #[test]
+    fn test_account_disputed() {
+        let mut account = Account::new(1);
+        let amount1 = Decimal::from_str("10.0").unwrap();
+        let amount2 = Decimal::from_str("2.5").unwrap();
+
+        account.deposit(amount1).unwrap();
+        account.deposit(amount2).unwrap();
+        account.dispute(amount1, 1).unwrap();
+        account.dispute(amount2, 2).unwrap();
+
+        let mut disputed: Vec<_> = account.disputed().collect();
+        disputed.sort();
+        assert_eq!(disputed, vec![(1, amount1), (2, amount2)]);
+    }
+
     #[test]
     fn test_account_resolve() {
         let mut account = Account::new(1);
@@ -182,6 +481,47 @@ mod tests {
         assert_eq!(account.total, amount);
     }
 
+    #[test]
+    fn test_account_resolve_all() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.deposit(amount).unwrap();
+        account.deposit(amount).unwrap();
+        account.dispute(amount, 1).unwrap();
+        account.dispute(amount, 2).unwrap();
+        account.dispute(amount, 3).unwrap();
+        assert_eq!(account.held, Decimal::from_str("30.0").unwrap());
+
+        assert!(account.resolve_all().is_ok());
+        assert_eq!(account.available, Decimal::from_str("30.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("30.0").unwrap());
+        assert!(account.disputed_transactions.is_empty());
+    }
+
+    #[test]
+    fn test_account_resolve_already_resolved() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.dispute(amount, 1).unwrap();
+        account.resolve(1).unwrap();
+
+        // Repeated resolve and chargeback are ignored without touching balances
+        assert!(matches!(account.resolve(1), Ok(Settlement::AlreadyResolved)));
+        assert!(matches!(account.chargeback(1), Ok(Settlement::AlreadyResolved)));
+        assert_eq!(account.available, amount);
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, amount);
+        assert!(!account.locked);
+
+        // Never disputed transactions are still rejected
+        assert!(account.resolve(2).is_err());
+    }
+
     #[test]
     fn test_account_chargeback() {
         let mut account = Account::new(1);
@@ -196,6 +536,28 @@ mod tests {
         assert!(account.locked);
     }
 
+    #[test]
+    fn test_chargeback_before_dispute() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        assert!(matches!(account.chargeback(1), Err(AccountError::TransactionNotDisputed)));
+        account.deposit(amount).unwrap();
+        assert!(matches!(account.chargeback(1), Err(AccountError::TransactionNotDisputed)));
+        assert_eq!(account.available, amount);
+        assert_eq!(account.total, amount);
+        assert!(!account.locked);
+
+        // Once locked by another chargeback, a disputed transaction can still be charged back
+        account.deposit(amount).unwrap();
+        account.dispute(amount, 1).unwrap();
+        account.dispute(amount, 2).unwrap();
+        account.chargeback(1).unwrap();
+        assert!(matches!(account.chargeback(3), Err(AccountError::TransactionNotDisputed)));
+        assert!(account.chargeback(2).is_ok());
+        assert_eq!(account.total, Decimal::ZERO);
+    }
+
     #[test]
     fn test_insufficient_funds() {
         let mut account = Account::new(1);
@@ -204,6 +566,56 @@ mod tests {
         assert!(account.withdraw(amount).is_err());
     }
 
+    #[test]
+    fn test_failed_withdrawals() {
+        let mut account = Account::new(1);
+        account.deposit(Decimal::from(10)).unwrap();
+
+        assert!(account.withdraw(Decimal::from(20)).is_err());
+        assert!(account.withdraw(Decimal::from(20)).is_err());
+        assert_eq!(account.failed_withdrawals, 2);
+
+        account.withdraw(Decimal::from(5)).unwrap();
+        assert_eq!(account.failed_withdrawals, 0);
+    }
+
+    #[test]
+    fn test_withdrawal_within_overdraft() {
+        let mut account = Account::new(1);
+        let limit = Decimal::from_str("50.0").unwrap();
+
+        account.deposit(Decimal::from_str("10.0").unwrap()).unwrap();
+        assert!(account.withdraw_with_overdraft(Decimal::from_str("60.0").unwrap(), limit).is_ok());
+        assert_eq!(account.available, Decimal::from_str("-50.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("-50.0").unwrap());
+    }
+
+    #[test]
+    fn test_withdrawal_beyond_overdraft() {
+        let mut account = Account::new(1);
+        let limit = Decimal::from_str("50.0").unwrap();
+
+        account.deposit(Decimal::from_str("10.0").unwrap()).unwrap();
+        assert!(account.withdraw_with_overdraft(Decimal::from_str("60.01").unwrap(), limit).is_err());
+        assert_eq!(account.available, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("10.0").unwrap());
+    }
+
+    #[test]
+    fn test_dispute_with_overdrawn_balance() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.withdraw_with_overdraft(Decimal::from_str("15.0").unwrap(), amount).unwrap();
+
+        // Nothing is left to hold, the available balance must not go further negative
+        assert!(account.dispute(amount, 1).is_ok());
+        assert_eq!(account.available, Decimal::from_str("-5.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("-5.0").unwrap());
+    }
+
     #[test]
     fn test_locked_account() {
         let mut account = Account::new(1);
@@ -217,4 +629,250 @@ mod tests {
         assert!(account.deposit(amount).is_err());
         assert!(account.withdraw(amount).is_err());
     }
+
+    #[test]
+    fn test_frozen_account() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.freeze();
+        assert!(matches!(account.deposit(amount), Err(AccountError::AccountFrozen)));
+        assert!(matches!(account.withdraw(amount), Err(AccountError::AccountFrozen)));
+        assert!(matches!(account.dispute(amount, 1), Err(AccountError::AccountFrozen)));
+        assert_eq!(account.available, amount);
+        assert!(!account.locked);
+
+        account.unfreeze();
+        assert!(account.deposit(amount).is_ok());
+        assert_eq!(account.total, amount + amount);
+    }
+
+    #[test]
+    fn test_charged_back_transaction_is_finalized() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.dispute(amount, 1).unwrap();
+        account.chargeback(1).unwrap();
+
+        assert!(matches!(account.dispute(amount, 1), Err(AccountError::TransactionFinalized)));
+        assert!(matches!(account.resolve(1), Err(AccountError::TransactionFinalized)));
+        assert!(matches!(account.chargeback(1), Err(AccountError::TransactionFinalized)));
+    }
+
+    #[test]
+    fn test_dispute_portions() {
+        let mut account = Account::new(1);
+        account.deposit(Decimal::from_str("10.0").unwrap()).unwrap();
+
+        account.dispute_portion(Decimal::from_str("3.0").unwrap(), Decimal::from(10), 1, true).unwrap();
+        account.dispute_portion(Decimal::from_str("2.0").unwrap(), Decimal::from(10), 1, true).unwrap();
+        assert_eq!(account.disputed_transactions[&1], Decimal::from_str("5.0").unwrap());
+        assert_eq!(account.held, Decimal::from_str("5.0").unwrap());
+
+        // Resolving releases every disputed portion at once
+        account.resolve(1).unwrap();
+        assert_eq!(account.available, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_dispute_portions_beyond_original() {
+        let mut account = Account::new(1);
+        let original = Decimal::from(10);
+        account.deposit(original).unwrap();
+        account.withdraw(Decimal::from(8)).unwrap();
+
+        // Only 2 can be held, but the portions still count in full against the original amount
+        account.dispute_portion(Decimal::from(5), original, 1, true).unwrap();
+        assert_eq!(account.held, Decimal::from(2));
+        assert_eq!(account.disputed_amount(1), Decimal::from(5));
+        assert!(matches!(account.dispute_portion(Decimal::from(6), original, 1, true),
+            Err(AccountError::DisputeExceedsTransaction)));
+        account.dispute_portion(Decimal::from(5), original, 1, true).unwrap();
+
+        // The whole amount can be disputed again once resolved
+        account.resolve(1).unwrap();
+        assert_eq!(account.disputed_amount(1), Decimal::ZERO);
+        account.dispute_portion(original, original, 1, true).unwrap();
+
+        account.dispute_withdrawal_portion(Decimal::from(8), Decimal::from(8), 2).unwrap();
+        assert!(matches!(account.dispute_withdrawal_portion(Decimal::from(1), Decimal::from(8), 2),
+            Err(AccountError::DisputeExceedsTransaction)));
+    }
+
+    #[test]
+    fn test_minor_units_amounts() {
+        // Balances in cents
+        let mut account: Account<MinorUnits> = Account::new(1);
+
+        account.deposit(MinorUnits(1000)).unwrap();
+        account.deposit(MinorUnits(250)).unwrap();
+        account.withdraw(MinorUnits(300)).unwrap();
+        assert!(matches!(account.withdraw(MinorUnits(1000)), Err(AccountError::InsufficientFunds)));
+
+        account.dispute(MinorUnits(250), 2).unwrap();
+        assert_eq!((account.available, account.held, account.total), (MinorUnits(700), MinorUnits(250), MinorUnits(950)));
+
+        account.chargeback(2).unwrap();
+        assert_eq!((account.available, account.held, account.total), (MinorUnits(700), MinorUnits::ZERO, MinorUnits(700)));
+        assert!(account.locked);
+        assert!(account.check_invariant().is_ok());
+    }
+
+    #[test]
+    fn test_minor_units_overflow() {
+        let mut account: Account<MinorUnits> = Account::new(1);
+        account.deposit(MinorUnits(i64::MAX - 10)).unwrap();
+
+        // An overflowing operation is rejected and leaves the balances untouched
+        assert!(matches!(account.deposit(MinorUnits(11)), Err(AccountError::AmountOverflow)));
+        assert!(matches!(account.withdraw_with_overdraft(MinorUnits(1), MinorUnits(11)),
+            Err(AccountError::AmountOverflow)));
+        assert_eq!((account.available, account.total), (MinorUnits(i64::MAX - 10), MinorUnits(i64::MAX - 10)));
+
+        account.withdraw(MinorUnits(100)).unwrap();
+        account.dispute_withdrawal(MinorUnits(100), 2, false).unwrap();
+        assert!(matches!(account.dispute_withdrawal(MinorUnits(11), 3, false), Err(AccountError::AmountOverflow)));
+        assert_eq!((account.held, account.total), (MinorUnits(100), MinorUnits(i64::MAX - 10)));
+        assert!(!account.disputed_transactions.contains_key(&3));
+
+        // Charging back credits the withdrawn funds back to available, which still fits
+        account.chargeback(2).unwrap();
+        assert_eq!((account.available, account.held, account.total),
+            (MinorUnits(i64::MAX - 10), MinorUnits::ZERO, MinorUnits(i64::MAX - 10)));
+    }
+
+    #[test]
+    fn test_resolve_on_locked_account() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.deposit(amount).unwrap();
+        account.dispute(amount, 1).unwrap();
+        account.dispute(amount, 2).unwrap();
+        account.chargeback(1).unwrap();
+        assert!(account.locked);
+
+        // The funds held for the other dispute can still be released
+        assert!(account.resolve(2).is_ok());
+        assert_eq!(account.available, amount);
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, amount);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_balance_invariant() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+
+        account.deposit(amount).unwrap();
+        account.deposit(amount).unwrap();
+        account.withdraw(Decimal::from_str("5.0").unwrap()).unwrap();
+        account.dispute(amount, 1).unwrap();
+        assert!(account.check_invariant().is_ok());
+        account.chargeback(1).unwrap();
+        assert!(account.check_invariant().is_ok());
+
+        account.held += amount;
+        assert!(matches!(account.check_invariant(), Err(AccountError::BalanceInvariantViolated)));
+    }
+
+    #[test]
+    fn test_free_balance_and_equity() {
+        let mut account = Account::new(1);
+        let amount = Decimal::from_str("10.0").unwrap();
+        let assert_balances = |account: &Account, free_balance: &str| {
+            assert_eq!(account.free_balance(), Decimal::from_str(free_balance).unwrap());
+            assert_eq!(account.equity(), Some(account.total));
+        };
+
+        account.deposit(amount).unwrap();
+        account.deposit(amount).unwrap();
+        assert_balances(&account, "20.0");
+        account.dispute(amount, 1).unwrap();
+        assert_balances(&account, "10.0");
+        account.resolve(1).unwrap();
+        assert_balances(&account, "20.0");
+        account.dispute(amount, 2).unwrap();
+        account.chargeback(2).unwrap();
+        assert_balances(&account, "10.0");
+    }
+
+    #[test]
+    fn test_dispute_balance_movements() {
+        let amount = Decimal::from_str("20.0").unwrap();
+        let balances = |account: &Account| (account.available, account.held, account.total);
+        let decimals = |values: [&str; 3]| values.map(|value| Decimal::from_str(value).unwrap()).into();
+
+        // Disputed transaction, outcome, balances after the dispute, balances after the outcome,
+        // from 70 available after a deposit of 100 (tx 1) and a withdrawal of 30 (tx 2)
+        let cases = [
+            (1, "resolve", ["50", "20", "70"], ["70", "0", "70"]),
+            (1, "chargeback", ["50", "20", "70"], ["50", "0", "50"]),
+            (2, "resolve", ["70", "20", "90"], ["70", "0", "70"]),
+            (2, "chargeback", ["70", "20", "90"], ["90", "0", "90"]),
+        ];
+
+        for (tx_id, outcome, after_dispute, after_outcome) in cases {
+            let mut account = Account::new(1);
+            account.deposit(Decimal::from(100)).unwrap();
+            account.withdraw(Decimal::from(30)).unwrap();
+
+            if tx_id == 2 {
+                account.dispute_withdrawal(amount, tx_id, false).unwrap();
+            } else {
+                account.dispute(amount, tx_id).unwrap();
+            }
+            assert_eq!(balances(&account), decimals(after_dispute), "dispute of tx {}", tx_id);
+
+            match outcome {
+                "resolve" => account.resolve(tx_id).unwrap(),
+                _ => account.chargeback(tx_id).unwrap(),
+            };
+            assert_eq!(balances(&account), decimals(after_outcome), "{} of tx {}", outcome, tx_id);
+            assert!(account.check_invariant().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_disputes_after_withdrawal() {
+        let balances = |account: &Account| (account.available, account.held, account.total);
+        let decimals = |values: [&str; 3]| values.map(|value| Decimal::from_str(value).unwrap()).into();
+
+        // Outcomes of the disputes of tx 1 and tx 2 in that order, balances after each outcome,
+        // from 5 available after deposits of 10 (tx 1 and 2) and a withdrawal of 15, with both deposits disputed:
+        // the first dispute holds the 5 left, the second one holds nothing
+        let cases = [
+            ([(1, "resolve"), (2, "resolve")], [["5", "0", "5"], ["5", "0", "5"]]),
+            ([(1, "chargeback"), (2, "resolve")], [["0", "0", "0"], ["0", "0", "0"]]),
+            ([(2, "chargeback"), (1, "resolve")], [["0", "5", "5"], ["5", "0", "5"]]),
+            ([(2, "chargeback"), (1, "chargeback")], [["0", "5", "5"], ["0", "0", "0"]]),
+        ];
+
+        for (outcomes, after_outcomes) in cases {
+            let mut account = Account::new(1);
+            account.deposit(Decimal::from(10)).unwrap();
+            account.deposit(Decimal::from(10)).unwrap();
+            account.withdraw(Decimal::from(15)).unwrap();
+            account.dispute(Decimal::from(10), 1).unwrap();
+            account.dispute(Decimal::from(10), 2).unwrap();
+            assert_eq!(balances(&account), decimals(["0", "5", "5"]));
+
+            for ((tx_id, outcome), after_outcome) in outcomes.into_iter().zip(after_outcomes) {
+                match outcome {
+                    "resolve" => account.resolve(tx_id).unwrap(),
+                    _ => account.chargeback(tx_id).unwrap(),
+                };
+                assert_eq!(balances(&account), decimals(after_outcome), "{} of tx {}", outcome, tx_id);
+                assert!(account.check_invariant().is_ok());
+                assert!(account.available >= Decimal::ZERO && account.held >= Decimal::ZERO);
+                assert!(account.held <= account.total);
+            }
+        }
+    }
 }
@@ -0,0 +1,46 @@
+use rust_decimal::Decimal;
+use std::fmt;
+
+// Numeric type of account balances. The operations are checked, so that an integer type can report an overflow
+// rather than panic in debug builds and wrap in release ones
+pub trait Amount: Copy + PartialOrd + fmt::Debug + fmt::Display {
+    const ZERO: Self;
+
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+}
+
+impl Amount for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+}
+
+// Fixed-point amount as an integer count of minor units, e.g. cents, for callers who don't need Decimal's range
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinorUnits(pub i64);
+
+impl fmt::Display for MinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Amount for MinorUnits {
+    const ZERO: Self = MinorUnits(0);
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(MinorUnits)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(MinorUnits)
+    }
+}
@@ -0,0 +1,171 @@
+use crate::engine::TransactionEngineBuilder;
+use crate::engine_error::EngineError;
+use crate::transaction::TransactionType;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use toml_edit::{DocumentMut, Item, Value};
+
+// Version of the config file format, the only one the engine understands
+const CONFIG_VERSION: u32 = 1;
+
+// Engine options read from a TOML config file, keys are named after the builder setters.
+// Options left out of the file keep the builder defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    version: Option<u32>,
+    strict: Option<bool>,
+    lenient_amounts: Option<bool>,
+    precision_validation: Option<bool>,
+    internal_precision: Option<u32>,
+    output_precision: Option<u32>,
+    overdraft_limit: Option<Decimal>,
+    #[serde(default)]
+    client_overdraft_limits: HashMap<u16, Decimal>,
+    #[serde(default)]
+    currency_output_precisions: HashMap<String, u32>,
+    max_transaction_amount: Option<Decimal>,
+    min_deposit_amount: Option<Decimal>,
+    max_accounts: Option<usize>,
+    failed_withdrawals_lock_threshold: Option<u32>,
+    monotonic_tx_ids: Option<bool>,
+    withdrawal_disputes: Option<bool>,
+    enabled_types: Option<Vec<TransactionType>>,
+}
+
+impl EngineConfig {
+    pub fn from_toml(data: &str) -> Result<Self, EngineError> {
+        let document: DocumentMut = data.parse()
+            .map_err(|err| EngineError::InvalidConfig(format!("{}", err).trim_end().to_string()))?;
+
+        // TOML values map onto JSON ones, which lets serde do the validation of the config
+        let config: EngineConfig = serde_json::from_value(item_to_json(document.as_item()))
+            .map_err(|err| EngineError::InvalidConfig(err.to_string()))?;
+
+        if let Some(version) = config.version.filter(|version| *version != CONFIG_VERSION) {
+            return Err(EngineError::InvalidConfig(format!(
+                "unsupported config version {}, expected {}", version, CONFIG_VERSION)));
+        }
+
+        Ok(config)
+    }
+
+    pub fn apply(&self, mut builder: TransactionEngineBuilder) -> TransactionEngineBuilder {
+        if let Some(strict) = self.strict {
+            builder = builder.strict(strict);
+        }
+        if let Some(lenient_amounts) = self.lenient_amounts {
+            builder = builder.lenient_amounts(lenient_amounts);
+        }
+        if let Some(precision_validation) = self.precision_validation {
+            builder = builder.precision_validation(precision_validation);
+        }
+        if let Some(internal_precision) = self.internal_precision {
+            builder = builder.internal_precision(internal_precision);
+        }
+        if let Some(output_precision) = self.output_precision {
+            builder = builder.output_precision(output_precision);
+        }
+        if let Some(overdraft_limit) = self.overdraft_limit {
+            builder = builder.overdraft_limit(overdraft_limit);
+        }
+        for (client, limit) in &self.client_overdraft_limits {
+            builder = builder.client_overdraft_limit(*client, *limit);
+        }
+        for (currency, output_precision) in &self.currency_output_precisions {
+            builder = builder.currency_output_precision(currency.clone(), *output_precision);
+        }
+        if let Some(max_transaction_amount) = self.max_transaction_amount {
+            builder = builder.max_transaction_amount(max_transaction_amount);
+        }
+        if let Some(min_deposit_amount) = self.min_deposit_amount {
+            builder = builder.min_deposit_amount(min_deposit_amount);
+        }
+        if let Some(max_accounts) = self.max_accounts {
+            builder = builder.max_accounts(max_accounts);
+        }
+        if let Some(threshold) = self.failed_withdrawals_lock_threshold {
+            builder = builder.failed_withdrawals_lock_threshold(threshold);
+        }
+        if let Some(monotonic_tx_ids) = self.monotonic_tx_ids {
+            builder = builder.monotonic_tx_ids(monotonic_tx_ids);
+        }
+        if let Some(withdrawal_disputes) = self.withdrawal_disputes {
+            builder = builder.withdrawal_disputes(withdrawal_disputes);
+        }
+        if let Some(enabled_types) = &self.enabled_types {
+            builder = builder.enabled_types(enabled_types.iter().cloned());
+        }
+        builder
+    }
+}
+
+fn item_to_json(item: &Item) -> serde_json::Value {
+    match item {
+        Item::Value(value) => value_to_json(value),
+        Item::Table(table) => table.iter()
+            .map(|(key, item)| (key.to_string(), item_to_json(item)))
+            .collect(),
+        Item::ArrayOfTables(tables) => tables.iter()
+            .map(|table| item_to_json(&Item::Table(table.clone())))
+            .collect(),
+        Item::None => serde_json::Value::Null,
+    }
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::String(string) => string.value().clone().into(),
+        Value::Integer(integer) => (*integer.value()).into(),
+        // Kept as written, so that amounts such as 0.1 are not rounded through f64
+        Value::Float(float) => float.display_repr().into_owned().into(),
+        Value::Boolean(boolean) => (*boolean.value()).into(),
+        Value::Datetime(datetime) => datetime.value().to_string().into(),
+        Value::Array(array) => array.iter().map(value_to_json).collect(),
+        Value::InlineTable(table) => table.iter()
+            .map(|(key, value)| (key.to_string(), value_to_json(value)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_from_toml() {
+        let config = EngineConfig::from_toml("
+            version = 1
+            strict = true
+            overdraft_limit = 0.1
+            max_accounts = 10
+            enabled_types = [\"deposit\", \"withdrawal\"]
+
+            [client_overdraft_limits]
+            7 = \"25.5\"
+
+            [currency_output_precisions]
+            JPY = 0
+        ").unwrap();
+
+        assert_eq!(config.strict, Some(true));
+        assert_eq!(config.overdraft_limit, Some(Decimal::from_str("0.1").unwrap()));
+        assert_eq!(config.max_accounts, Some(10));
+        assert_eq!(config.client_overdraft_limits, HashMap::from([(7, Decimal::from_str("25.5").unwrap())]));
+        assert_eq!(config.currency_output_precisions, HashMap::from([("JPY".to_string(), 0)]));
+        assert_eq!(config.enabled_types, Some(vec![TransactionType::Deposit, TransactionType::Withdrawal]));
+        assert_eq!(config.lenient_amounts, None);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let error = |data| EngineConfig::from_toml(data).unwrap_err().to_string();
+
+        assert!(error("strict = ").starts_with("Invalid config: TOML parse error"));
+        assert!(error("stric = true").starts_with("Invalid config: unknown field `stric`"));
+        assert!(error("strict = \"yes\"").starts_with("Invalid config: invalid type"));
+        assert_eq!(error("version = 2"), "Invalid config: unsupported config version 2, expected 1");
+    }
+}
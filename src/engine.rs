@@ -1,194 +1,3772 @@
-use crate::account::Account;
+use crate::account::{Account, AccountError, AccountRecord, Settlement};
 use crate::engine_error::EngineError;
-use crate::transaction::{Transaction, TransactionType};
-use csv::{Reader, Writer};
+use crate::snapshot::EngineSnapshot;
+use crate::transaction::{self, Transaction, TransactionType};
+use csv::{Reader, StringRecord, Trim, Writer};
+use flate2::read::MultiGzDecoder;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
-use std::io::Read;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
-#[derive(Debug)]
-pub struct TransactionEngine {
-    accounts: HashMap<u16, Account>,
-    transaction_history: HashMap<u32, Transaction>,
+// Maximum number of decimal places accepted on input amounts when precision validation is enabled
+const MAX_INPUT_SCALE: u32 = 4;
+
+// Number of decimal places of the output amounts, unless configured otherwise
+const DEFAULT_OUTPUT_PRECISION: u32 = 4;
+
+// Number of rows processed between two checks of the control file
+const CONTROL_FILE_CHECK_ROWS: usize = 1000;
+
+// First bytes of a gzip stream
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+// A change of an account's locked state, locked by a chargeback or frozen, along with the transaction that triggered it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LockChange {
+    pub client: u16,
+    pub tx: u32,
+    pub locked: bool,
 }
 
-impl TransactionEngine {
-    pub fn new() -> Self {
+// Balances of an account right after a transaction was applied to it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceSnapshot {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl BalanceSnapshot {
+    fn rounded(&self, output_precision: u32) -> Self {
         Self {
-            accounts: HashMap::new(),
-            transaction_history: HashMap::new(),
+            available: self.available.round_dp(output_precision),
+            held: self.held.round_dp(output_precision),
+            total: self.total.round_dp(output_precision),
+            ..self.clone()
         }
     }
+}
 
-    pub fn process_transactions_from_reader<R: Read>(&mut self, reader: &mut Reader<R>) -> Result<(), EngineError> {
-        for result in reader.deserialize() {
-            let transaction: Transaction = result?;
-            self.process_transaction(transaction)?;
-        }
+// Line of the audit ledger: the balances of an account right after each applied transaction, for all clients
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+    pub tx: u32,
+    pub client: u16,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub available_after: Decimal,
+    pub held_after: Decimal,
+    pub total_after: Decimal,
+}
 
-        Ok(())
+impl LedgerEntry {
+    fn rounded(&self, output_precision: u32) -> Self {
+        Self {
+            available_after: self.available_after.round_dp(output_precision),
+            held_after: self.held_after.round_dp(output_precision),
+            total_after: self.total_after.round_dp(output_precision),
+            ..self.clone()
+        }
     }
+}
 
-    fn process_transaction(&mut self, transaction: Transaction) -> Result<(), EngineError> {
-        // Validate transaction
-        self.validate_transaction(&transaction)?;
+// Opening balance of a client, credited as available funds before processing transactions
+#[derive(Debug, Clone, Deserialize)]
+pub struct SeedBalance {
+    pub client: u16,
+    pub balance: Decimal,
+}
 
-        let res = match transaction.transaction_type {
-            TransactionType::Deposit => self.process_deposit(&transaction),
-            TransactionType::Withdrawal => self.process_withdrawal(&transaction),
-            TransactionType::Dispute => self.process_dispute(&transaction),
-            TransactionType::Resolve => self.process_resolve(&transaction),
-            TransactionType::Chargeback => self.process_chargeback(&transaction),
-        };
+// What to do when the same client appears several times in the seed balances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedDuplicatePolicy {
+    #[default]
+    Error,
+    Sum,
+    LastWins,
+}
+
+// How rejected transactions are reported on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    // One JSON object per line
+    Json,
+}
+
+#[derive(Serialize)]
+struct Rejection {
+    tx: u32,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Diagnostic<'a> {
+    message: &'a str,
+}
 
-        if let Err(e) = res {
-            // Log the error but continue processing other transactions
-            eprintln!("Ignoring error while processing transaction {}: {}", transaction.tx, e);
+impl ErrorFormat {
+    fn format_rejection(self, tx: u32, err: &EngineError) -> String {
+        match self {
+            ErrorFormat::Human => format!("Ignoring error while processing transaction {}: {}", tx, err),
+            ErrorFormat::Json => serde_json::to_string(&Rejection { tx, reason: err.reason() })
+                .expect("rejections always serialize"),
         }
-        Ok(())
     }
 
-    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), EngineError> {
-        // Check if transaction requires amount but doesn't have one
-        if transaction.requires_amount() && transaction.amount.is_none() {
-            return Err(EngineError::InvalidTransaction(
-                "Deposit and withdrawal transactions must have an amount".to_string(),
-            ));
+    // Write a diagnostic other than a rejection to stderr, as a {"message": ...} object in the JSON format
+    pub fn log(self, message: impl fmt::Display) {
+        match self {
+            ErrorFormat::Human => eprintln!("{}", message),
+            ErrorFormat::Json => eprintln!("{}", serde_json::to_string(&Diagnostic { message: &message.to_string() })
+                .expect("diagnostics always serialize")),
         }
+    }
+}
 
-        // Check if dispute-related transaction has an amount (it shouldn't)
-        if transaction.is_dispute_related() && transaction.amount.is_some() {
-            return Err(EngineError::InvalidTransaction(
-                "Dispute, resolve, and chargeback transactions should not have an amount".to_string(),
-            ));
+// How the locked column is written in the CSV output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoolFormat {
+    // true or false
+    #[default]
+    Text,
+    // 1 or 0
+    Numeric,
+    // yes or no
+    YesNo,
+}
+
+impl BoolFormat {
+    pub fn format(self, value: bool) -> &'static str {
+        match (self, value) {
+            (BoolFormat::Text, true) => "true",
+            (BoolFormat::Text, false) => "false",
+            (BoolFormat::Numeric, true) => "1",
+            (BoolFormat::Numeric, false) => "0",
+            (BoolFormat::YesNo, true) => "yes",
+            (BoolFormat::YesNo, false) => "no",
         }
+    }
+}
 
-        // Check for negative amounts
-        if let Some(amount) = transaction.amount {
-            if amount <= Decimal::ZERO {
-                return Err(EngineError::InvalidTransaction(
-                    "Transaction amount must be positive".to_string(),
-                ));
-            }
+// Order of the accounts in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputSort {
+    // Ascending client id
+    #[default]
+    Client,
+    // Descending total balance, ties broken by ascending client id
+    Total,
+}
+
+// Account record as written in the CSV output, with the locked column in the configured format
+#[derive(Serialize)]
+struct CsvAccountRecord {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: &'static str,
+}
+
+impl CsvAccountRecord {
+    fn new(record: &AccountRecord, bool_format: BoolFormat) -> Self {
+        Self {
+            client: record.client,
+            available: record.available,
+            held: record.held,
+            total: record.total,
+            locked: bool_format.format(record.locked),
         }
+    }
+}
 
-        // Check for duplicate transaction IDs for deposit/withdrawal
-        if matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
-            if self.transaction_history.contains_key(&transaction.tx) {
-                return Err(EngineError::InvalidTransaction(
-                    format!("Duplicate transaction ID: {}", transaction.tx),
-                ));
-            }
+// Account record with the balances as integer amounts of minor units, e.g. cents at scale 2
+#[derive(Serialize)]
+struct MinorUnitsAccountRecord {
+    client: u16,
+    available_minor: i128,
+    held_minor: i128,
+    total_minor: i128,
+    locked: &'static str,
+}
+
+impl MinorUnitsAccountRecord {
+    const HEADERS: [&'static str; 5] = ["client", "available_minor", "held_minor", "total_minor", "locked"];
+
+    fn new(record: &AccountRecord, scale: u32, bool_format: BoolFormat) -> Result<Self, EngineError> {
+        Ok(Self {
+            client: record.client,
+            available_minor: Self::minor_units(record.available, scale, record.client)?,
+            held_minor: Self::minor_units(record.held, scale, record.client)?,
+            total_minor: Self::minor_units(record.total, scale, record.client)?,
+            locked: bool_format.format(record.locked),
+        })
+    }
+
+    fn minor_units(amount: Decimal, scale: u32, client: u16) -> Result<i128, EngineError> {
+        to_minor_units(amount, scale).ok_or_else(|| EngineError::AccountError(
+            format!("balance {} of client {} is not a whole number of minor units at scale {}", amount, client, scale),
+        ))
+    }
+}
+
+// Amount multiplied by 10^scale, if that leaves no fractional part
+fn to_minor_units(amount: Decimal, scale: u32) -> Option<i128> {
+    let factor = Decimal::try_from_i128_with_scale(10i128.checked_pow(scale)?, 0).ok()?;
+    let minor_units = amount.checked_mul(factor)?;
+    if minor_units.fract().is_zero() { minor_units.to_i128() } else { None }
+}
+
+// Configuration of a TransactionEngine, with chained setters
+#[derive(Debug, Clone)]
+pub struct TransactionEngineBuilder {
+    strict: bool,
+    lenient_amounts: bool,
+    overdraft_limit: Decimal,
+    client_overdraft_limits: HashMap<u16, Decimal>,
+    validate_precision: bool,
+    allow_schema_drift: bool,
+    timeseries_client: Option<u16>,
+    seed_duplicate_policy: SeedDuplicatePolicy,
+    control_file: Option<(PathBuf, Duration)>,
+    max_transaction_amount: Option<Decimal>,
+    min_deposit_amount: Decimal,
+    internal_precision: Option<u32>,
+    output_precision: u32,
+    currency_output_precisions: HashMap<String, u32>,
+    chronological: bool,
+    strict_disputes: bool,
+    lenient_resolve: bool,
+    error_format: ErrorFormat,
+    bool_format: BoolFormat,
+    output_sort: OutputSort,
+    minor_units_scale: Option<u32>,
+    deferred_disputes: bool,
+    history_window: Option<usize>,
+    withdrawal_fee: Decimal,
+    withdrawal_fee_percentage: Decimal,
+    signed_amounts: bool,
+    require_existing_accounts: bool,
+    provisioned_clients: HashSet<u16>,
+    thousands_separators: bool,
+    trim: Trim,
+    allow_truncated: bool,
+    verbose: bool,
+    ledger: bool,
+    monotonic_tx_ids: bool,
+    max_accounts: Option<usize>,
+    failed_withdrawals_lock_threshold: Option<u32>,
+    disputable_types: HashSet<TransactionType>,
+    enabled_types: Option<HashSet<TransactionType>>,
+    progress_interval: Option<usize>,
+    flagged_held_threshold: Option<Decimal>,
+    flagged_disputes_threshold: Option<usize>,
+    stop_after_tx: Option<u32>,
+    stop_flag: Option<Arc<AtomicBool>>,
+}
+
+impl Default for TransactionEngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionEngineBuilder {
+    pub fn new() -> Self {
+        Self {
+            strict: false,
+            lenient_amounts: false,
+            overdraft_limit: Decimal::ZERO,
+            client_overdraft_limits: HashMap::new(),
+            validate_precision: false,
+            allow_schema_drift: false,
+            timeseries_client: None,
+            seed_duplicate_policy: SeedDuplicatePolicy::default(),
+            control_file: None,
+            max_transaction_amount: None,
+            min_deposit_amount: Decimal::ZERO,
+            internal_precision: None,
+            output_precision: DEFAULT_OUTPUT_PRECISION,
+            currency_output_precisions: HashMap::new(),
+            chronological: false,
+            strict_disputes: false,
+            lenient_resolve: false,
+            error_format: ErrorFormat::default(),
+            bool_format: BoolFormat::default(),
+            output_sort: OutputSort::default(),
+            minor_units_scale: None,
+            deferred_disputes: false,
+            history_window: None,
+            withdrawal_fee: Decimal::ZERO,
+            withdrawal_fee_percentage: Decimal::ZERO,
+            signed_amounts: false,
+            require_existing_accounts: false,
+            provisioned_clients: HashSet::new(),
+            thousands_separators: false,
+            trim: Trim::All,
+            allow_truncated: false,
+            verbose: false,
+            ledger: false,
+            monotonic_tx_ids: false,
+            max_accounts: None,
+            failed_withdrawals_lock_threshold: None,
+            disputable_types: HashSet::from([TransactionType::Deposit]),
+            enabled_types: None,
+            progress_interval: None,
+            flagged_held_threshold: None,
+            flagged_disputes_threshold: None,
+            stop_after_tx: None,
+            stop_flag: None,
         }
+    }
 
-        Ok(())
+    // In strict mode, the first rejected transaction aborts processing instead of being logged
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    fn process_deposit(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
-        let amount = transaction.amount.unwrap(); // Safe because we validated
-        let account = self.accounts.entry(transaction.client).or_insert_with(|| Account::new(transaction.client));
-        
-        account.deposit(amount)?;
-        
-        // Store transaction for potential disputes
-        self.transaction_history.insert(transaction.tx, transaction.clone());
-        Ok(())
+    // When enabled, stray trailing bytes (null, BOM, ...) are stripped from amounts before parsing
+    pub fn lenient_amounts(mut self, lenient_amounts: bool) -> Self {
+        self.lenient_amounts = lenient_amounts;
+        self
     }
 
-    fn process_withdrawal(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
-        let amount = transaction.amount.unwrap(); // Safe because we validated
-        let account = self.accounts.entry(transaction.client).or_insert_with(|| Account::new(transaction.client));
-        
-        account.withdraw(amount)?;
-        
-        // Store transaction for potential disputes
-        self.transaction_history.insert(transaction.tx, transaction.clone());
-        Ok(())
+    // Default overdraft limit, applied to every client without a specific override
+    pub fn overdraft_limit(mut self, limit: Decimal) -> Self {
+        self.overdraft_limit = limit;
+        self
     }
 
-    fn process_dispute(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
-        // Find the original transaction
-        let original_transaction = self.transaction_history.get(&transaction.tx)
-            .ok_or_else(|| EngineError::InvalidTransaction(
-                format!("Cannot dispute non-existent transaction: {}", transaction.tx)
-            ))?;
+    pub fn client_overdraft_limit(mut self, client: u16, limit: Decimal) -> Self {
+        self.client_overdraft_limits.insert(client, limit);
+        self
+    }
 
-        // Verify client matches
-        if original_transaction.client != transaction.client {
-            return Err(EngineError::InvalidTransaction(
-                "Cannot dispute transaction from different client".to_string(),
-            ));
-        }
+    // Reject amounts with more than 4 decimal places instead of rounding them at output time
+    pub fn precision_validation(mut self, validate_precision: bool) -> Self {
+        self.validate_precision = validate_precision;
+        self
+    }
 
-        // Only deposits can be disputed
-        if !matches!(original_transaction.transaction_type, TransactionType::Deposit) {
-            return Err(EngineError::InvalidTransaction(
-                "Only deposit transactions can be disputed".to_string(),
-            ));
-        }
+    // By default, every reader processed by the engine must have the same headers as the first one
+    pub fn allow_schema_drift(mut self, allow_schema_drift: bool) -> Self {
+        self.allow_schema_drift = allow_schema_drift;
+        self
+    }
 
-        let amount = original_transaction.amount.unwrap();
-        let account = self.accounts.get_mut(&transaction.client)
-            .ok_or_else(|| EngineError::AccountError("Account not found".to_string()))?;
+    // Record the balances of this client after each transaction applied to its account
+    pub fn timeseries_client(mut self, client: Option<u16>) -> Self {
+        self.timeseries_client = client;
+        self
+    }
 
-        account.dispute(amount, transaction.tx)?;
-        Ok(())
+    // Record the balances of every account after each transaction applied to it
+    pub fn ledger(mut self, ledger: bool) -> Self {
+        self.ledger = ledger;
+        self
     }
 
-    fn process_resolve(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
-        // Find the original transaction
-        let original_transaction = self.transaction_history.get(&transaction.tx)
-            .ok_or_else(|| EngineError::InvalidTransaction(
-                format!("Cannot resolve non-existent transaction: {}", transaction.tx)
-            ))?;
+    // Reject deposits and withdrawals whose tx id is not above all the previous ones, for feeds with increasing ids
+    pub fn monotonic_tx_ids(mut self, monotonic_tx_ids: bool) -> Self {
+        self.monotonic_tx_ids = monotonic_tx_ids;
+        self
+    }
 
-        // Verify client matches
-        if original_transaction.client != transaction.client {
-            return Err(EngineError::InvalidTransaction(
-                "Cannot resolve transaction from different client".to_string(),
-            ));
+    // Reject the deposits, withdrawals and seed balances that would create more accounts than this
+    pub fn max_accounts(mut self, max_accounts: usize) -> Self {
+        self.max_accounts = Some(max_accounts);
+        self
+    }
+
+    // Lock an account after this many withdrawals in a row rejected for insufficient funds, as an anti-abuse measure
+    pub fn failed_withdrawals_lock_threshold(mut self, threshold: u32) -> Self {
+        self.failed_withdrawals_lock_threshold = Some(threshold);
+        self
+    }
+
+    // Transaction types that can be disputed, deposits only by default
+    pub fn disputable_types(mut self, types: impl IntoIterator<Item = TransactionType>) -> Self {
+        self.disputable_types = types.into_iter().collect();
+        self
+    }
+
+    // Only process transactions of these types, rows of the other types are skipped. Every type is enabled by default
+    pub fn enabled_types(mut self, types: impl IntoIterator<Item = TransactionType>) -> Self {
+        self.enabled_types = Some(types.into_iter().collect());
+        self
+    }
+
+    // Allow disputing withdrawals, whose funds are credited back to the account on a chargeback
+    pub fn withdrawal_disputes(mut self, withdrawal_disputes: bool) -> Self {
+        if withdrawal_disputes {
+            self.disputable_types.insert(TransactionType::Withdrawal);
+        } else {
+            self.disputable_types.remove(&TransactionType::Withdrawal);
         }
+        self
+    }
 
-        let account = self.accounts.get_mut(&transaction.client)
-            .ok_or_else(|| EngineError::AccountError("Account not found".to_string()))?;
+    // Log the number of processed rows every `rows` rows, as feedback on large inputs
+    pub fn progress_interval(mut self, rows: usize) -> Self {
+        self.progress_interval = Some(rows);
+        self
+    }
 
-        account.resolve(transaction.tx)?;
-        Ok(())
+    // Flag the clients holding more than this amount, see flagged_clients
+    pub fn flagged_held_threshold(mut self, threshold: Decimal) -> Self {
+        self.flagged_held_threshold = Some(threshold);
+        self
     }
 
-    fn process_chargeback(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
-        // Find the original transaction
-        let original_transaction = self.transaction_history.get(&transaction.tx)
-            .ok_or_else(|| EngineError::InvalidTransaction(
-                format!("Cannot chargeback non-existent transaction: {}", transaction.tx)
-            ))?;
+    // Flag the clients with more than this number of open disputes, see flagged_clients
+    pub fn flagged_disputes_threshold(mut self, threshold: usize) -> Self {
+        self.flagged_disputes_threshold = Some(threshold);
+        self
+    }
 
-        // Verify client matches
-        if original_transaction.client != transaction.client {
-            return Err(EngineError::InvalidTransaction(
-                "Cannot chargeback transaction from different client".to_string(),
-            ));
+    // Stop processing right after the transaction with this tx id, ignoring the rest of the input
+    pub fn stop_after_tx(mut self, tx: u32) -> Self {
+        self.stop_after_tx = Some(tx);
+        self
+    }
+
+    // Stop processing at the next row once this flag is set, e.g. from a signal handler, keeping the state so far
+    pub fn stop_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.stop_flag = Some(flag);
+        self
+    }
+
+    pub fn seed_duplicate_policy(mut self, policy: SeedDuplicatePolicy) -> Self {
+        self.seed_duplicate_policy = policy;
+        self
+    }
+
+    // Processing pauses while the control file exists, its presence is checked every poll interval
+    pub fn control_file(mut self, path: impl Into<PathBuf>, poll_interval: Duration) -> Self {
+        self.control_file = Some((path.into(), poll_interval));
+        self
+    }
+
+    // Reject deposits and withdrawals above this amount, to catch data-entry errors
+    pub fn max_transaction_amount(mut self, max_amount: Decimal) -> Self {
+        self.max_transaction_amount = Some(max_amount);
+        self
+    }
+
+    // Reject deposits below this amount, to filter out dust deposits
+    pub fn min_deposit_amount(mut self, min_amount: Decimal) -> Self {
+        self.min_deposit_amount = min_amount;
+        self
+    }
+
+    // Amounts are rounded to this number of decimal places before being applied, which bounds the precision
+    // of the balances as well. By default, the full precision of the input is kept.
+    pub fn internal_precision(mut self, internal_precision: u32) -> Self {
+        self.internal_precision = Some(internal_precision);
+        self
+    }
+
+    // Number of decimal places amounts are rounded to in the outputs
+    pub fn output_precision(mut self, output_precision: u32) -> Self {
+        self.output_precision = output_precision;
+        self
+    }
+
+    // Output precision of the accounts in this currency, e.g. 0 for JPY or 8 for BTC, instead of the default one
+    pub fn currency_output_precision(mut self, currency: impl Into<String>, output_precision: u32) -> Self {
+        self.currency_output_precisions.insert(currency.into(), output_precision);
+        self
+    }
+
+    // Buffer all transactions and apply them sorted by timestamp when calling finish, instead of in input order
+    pub fn chronological_order(mut self, chronological: bool) -> Self {
+        self.chronological = chronological;
+        self
+    }
+
+    // In strict dispute mode, disputing more than the available funds is rejected rather than partially held
+    pub fn strict_disputes(mut self, strict_disputes: bool) -> Self {
+        self.strict_disputes = strict_disputes;
+        self
+    }
+
+    // Ignore resolves of transactions that are not disputed, for upstreams sending redundant resolves
+    pub fn lenient_resolve(mut self, lenient_resolve: bool) -> Self {
+        self.lenient_resolve = lenient_resolve;
+        self
+    }
+
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    pub fn bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    pub fn output_sort(mut self, output_sort: OutputSort) -> Self {
+        self.output_sort = output_sort;
+        self
+    }
+
+    // Write the balances as integer amounts of minor units at this scale, e.g. cents at scale 2
+    pub fn minor_units(mut self, scale: u32) -> Self {
+        self.minor_units_scale = Some(scale);
+        self
+    }
+
+    // Park disputes, resolves and chargebacks of unknown transactions until their deposit is processed,
+    // those still unmatched are rejected by finish
+    pub fn deferred_disputes(mut self, deferred_disputes: bool) -> Self {
+        self.deferred_disputes = deferred_disputes;
+        self
+    }
+
+    // Only keep the last deposits and withdrawals in the history, older ones can't be disputed anymore.
+    // Transactions under dispute are kept until the dispute is settled. By default, the history is unbounded.
+    pub fn history_window(mut self, history_window: usize) -> Self {
+        self.history_window = Some(history_window);
+        self
+    }
+
+    // Flat fee debited on top of every withdrawal amount
+    pub fn withdrawal_fee(mut self, fee: Decimal) -> Self {
+        self.withdrawal_fee = fee;
+        self
+    }
+
+    // Fee debited on top of every withdrawal amount, as a percentage of it (1.5 for 1.5%)
+    pub fn withdrawal_fee_percentage(mut self, percentage: Decimal) -> Self {
+        self.withdrawal_fee_percentage = percentage;
+        self
+    }
+
+    // Read a deposit with a negative amount as a withdrawal of the opposite amount, and the other way around,
+    // instead of rejecting it
+    pub fn signed_amounts(mut self, signed_amounts: bool) -> Self {
+        self.signed_amounts = signed_amounts;
+        self
+    }
+
+    // Reject deposits and withdrawals of clients without an account, instead of creating it.
+    // Accounts exist once provisioned, seeded or restored from a snapshot.
+    pub fn require_existing_accounts(mut self, require_existing_accounts: bool) -> Self {
+        self.require_existing_accounts = require_existing_accounts;
+        self
+    }
+
+    // Clients whose account may be created when accounts must exist
+    pub fn provisioned_clients(mut self, clients: impl IntoIterator<Item = u16>) -> Self {
+        self.provisioned_clients.extend(clients);
+        self
+    }
+
+    // Accept amounts with thousands separators (1 000.50, 1_000.50 or a quoted "1,000.50"), which are stripped
+    // before parsing
+    pub fn thousands_separators(mut self, thousands_separators: bool) -> Self {
+        self.thousands_separators = thousands_separators;
+        self
+    }
+
+    // Whitespace trimmed from the input fields and headers, everything by default
+    pub fn trim(mut self, trim: Trim) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    // Log and ignore a last row of the input that can't be read, as left by an interrupted download, instead of
+    // failing on it
+    pub fn allow_truncated(mut self, allow_truncated: bool) -> Self {
+        self.allow_truncated = allow_truncated;
+        self
+    }
+
+    // Also log the diagnostics about expected input quirks, such as ignored redundant resolves
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn build(self) -> TransactionEngine {
+        TransactionEngine {
+            config: self,
+            accounts: HashMap::new(),
+            transaction_history: HashMap::new(),
+            rejected_transactions: HashMap::new(),
+            lock_changelog: Vec::new(),
+            schema: None,
+            timeseries: Vec::new(),
+            ledger: Vec::new(),
+            last_tx: None,
+            stopped: false,
+            pending_transactions: Vec::new(),
+            sequence: SequenceSource(Box::new(InputSequence::default())),
+            processed_count: 0,
+            applied_count: 0,
+            rejected_count: 0,
+            deferred_transactions: Vec::new(),
+            transaction_hook: None,
+            rejection_hook: None,
+            account_factory: AccountFactory(Box::new(Account::new)),
+            history_order: VecDeque::new(),
+            pruned_transactions: HashSet::new(),
+            collected_fees: Decimal::ZERO,
         }
+    }
+}
 
-        let account = self.accounts.get_mut(&transaction.client)
-            .ok_or_else(|| EngineError::AccountError("Account not found".to_string()))?;
+// Counts of a run, as summarized at the end of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProcessingStats {
+    // Transactions read from the input
+    pub processed: usize,
+    pub applied: usize,
+    // Transactions rejected and skipped, outside of strict mode
+    pub rejected: usize,
+    pub accounts: usize,
+}
 
-        account.chargeback(transaction.tx)?;
-        Ok(())
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "processed {} transactions, {} applied, {} rejected, {} accounts",
+               self.processed, self.applied, self.rejected, self.accounts)
     }
+}
 
-    pub fn output_account_balances_to_writer<W: Write>(&mut self, writer: &mut Writer<W>) -> Result<(), EngineError> {
-        // Sort accounts by client ID for consistent output
-        let mut sorted_accounts: Vec<_> = self.accounts.values().collect();
-        sorted_accounts.sort_by_key(|account| account.client);
-        
-        for account in sorted_accounts {
-            writer.serialize(account)?;
+// Callback invoked with each successfully applied transaction and the resulting state of its account
+type TransactionHookFn = dyn FnMut(&Transaction, &Account) + Send;
+
+struct TransactionHook(Box<TransactionHookFn>);
+
+impl fmt::Debug for TransactionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TransactionHook")
+    }
+}
+
+// Callback invoked with each rejected transaction and the reason it was rejected
+type RejectionHookFn = dyn FnMut(&Transaction, &EngineError) + Send;
+
+struct RejectionHook(Box<RejectionHookFn>);
+
+impl fmt::Debug for RejectionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RejectionHook")
+    }
+}
+
+// Creates the account of a client seen for the first time, e.g. with an opening balance
+type AccountFactoryFn = dyn Fn(u16) -> Account + Send;
+
+struct AccountFactory(Box<AccountFactoryFn>);
+
+impl fmt::Debug for AccountFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccountFactory")
+    }
+}
+
+// Source of the sequence numbers ordering the transactions buffered in chronological mode which have the same
+// timestamp, lowest first. Tests can substitute their own instead of relying on the input order.
+pub trait Sequence: Send {
+    fn next(&mut self) -> u64;
+}
+
+// Numbers the transactions in input order, which is the default order of transactions with the same timestamp
+#[derive(Debug, Default)]
+pub struct InputSequence(u64);
+
+impl Sequence for InputSequence {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+}
+
+struct SequenceSource(Box<dyn Sequence>);
+
+impl fmt::Debug for SequenceSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SequenceSource")
+    }
+}
+
+// Callbacks of an engine, taken from it while the engines processing its input files in parallel share them
+struct SharedCallbacks {
+    transaction_hook: Option<Arc<Mutex<TransactionHook>>>,
+    rejection_hook: Option<Arc<Mutex<RejectionHook>>>,
+    account_factory: Arc<Mutex<AccountFactory>>,
+    sequence: Arc<Mutex<SequenceSource>>,
+}
+
+// Sequence numbers drawn from the sequence of the engine the input files are processed for
+struct SharedSequence(Arc<Mutex<SequenceSource>>);
+
+impl Sequence for SharedSequence {
+    fn next(&mut self) -> u64 {
+        let SequenceSource(sequence) = &mut *self.0.lock().unwrap();
+        sequence.next()
+    }
+}
+
+impl SharedCallbacks {
+    fn take(engine: &mut TransactionEngine) -> Self {
+        let default_factory = AccountFactory(Box::new(Account::new));
+        let default_sequence = SequenceSource(Box::new(InputSequence::default()));
+        SharedCallbacks {
+            transaction_hook: engine.transaction_hook.take().map(|hook| Arc::new(Mutex::new(hook))),
+            rejection_hook: engine.rejection_hook.take().map(|hook| Arc::new(Mutex::new(hook))),
+            account_factory: Arc::new(Mutex::new(std::mem::replace(&mut engine.account_factory, default_factory))),
+            sequence: Arc::new(Mutex::new(std::mem::replace(&mut engine.sequence, default_sequence))),
         }
-        
-        writer.flush()?;
-        Ok(())
     }
+
+    fn install(&self, engine: &mut TransactionEngine) {
+        if let Some(hook) = &self.transaction_hook {
+            let hook = Arc::clone(hook);
+            engine.transaction_hook = Some(TransactionHook(Box::new(move |transaction, account| {
+                let TransactionHook(hook) = &mut *hook.lock().unwrap();
+                hook(transaction, account)
+            })));
+        }
+        if let Some(hook) = &self.rejection_hook {
+            let hook = Arc::clone(hook);
+            engine.rejection_hook = Some(RejectionHook(Box::new(move |transaction, err| {
+                let RejectionHook(hook) = &mut *hook.lock().unwrap();
+                hook(transaction, err)
+            })));
+        }
+        let factory = Arc::clone(&self.account_factory);
+        engine.account_factory = AccountFactory(Box::new(move |client| {
+            let AccountFactory(factory) = &*factory.lock().unwrap();
+            factory(client)
+        }));
+        engine.sequence = SequenceSource(Box::new(SharedSequence(Arc::clone(&self.sequence))));
+    }
+
+    // Give the callbacks back to their engine, once the engines sharing them are dropped
+    fn restore(self, engine: &mut TransactionEngine) {
+        fn unshare<T>(shared: Arc<Mutex<T>>) -> T {
+            Arc::into_inner(shared)
+                .expect("the engines sharing the callbacks are dropped")
+                .into_inner()
+                .unwrap()
+        }
+        engine.transaction_hook = self.transaction_hook.map(unshare);
+        engine.rejection_hook = self.rejection_hook.map(unshare);
+        engine.account_factory = unshare(self.account_factory);
+        engine.sequence = unshare(self.sequence);
+    }
+}
+
+#[derive(Debug)]
+pub struct TransactionEngine {
+    // Configuration the engine was built with, the options are read from it rather than copied
+    config: TransactionEngineBuilder,
+    accounts: HashMap<u16, Account>,
+    transaction_history: HashMap<u32, Transaction>,
+    rejected_transactions: HashMap<u32, Transaction>,
+    lock_changelog: Vec<LockChange>,
+    schema: Option<StringRecord>,
+    timeseries: Vec<BalanceSnapshot>,
+    ledger: Vec<LedgerEntry>,
+    // Highest tx id of the deposits and withdrawals seen so far
+    last_tx: Option<u32>,
+    // Whether the stop_after_tx transaction was processed
+    stopped: bool,
+    // Buffered in chronological mode, along with their sequence number
+    pending_transactions: Vec<(u64, Transaction)>,
+    sequence: SequenceSource,
+    processed_count: usize,
+    applied_count: usize,
+    rejected_count: usize,
+    deferred_transactions: Vec<Transaction>,
+    transaction_hook: Option<TransactionHook>,
+    rejection_hook: Option<RejectionHook>,
+    account_factory: AccountFactory,
+    // Ids of the history in insertion order, only tracked with a history window
+    history_order: VecDeque<u32>,
+    // Only the ids of pruned transactions are kept, to reject their disputes and ignore their replays
+    pruned_transactions: HashSet<u32>,
+    // Sum of the fees debited from all accounts
+    collected_fees: Decimal,
+}
+
+// Open an input file, decompressing it when it starts with the gzip magic bytes whatever its extension
+pub fn open_input_file(path: impl AsRef<Path>) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC_BYTES) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// Write balances as CSV, for callers selecting a subset of the engine's account records
+pub fn write_account_records<W: Write>(
+    records: &[AccountRecord],
+    bool_format: BoolFormat,
+    writer: &mut Writer<W>,
+) -> Result<(), EngineError> {
+    // The header is only written along with the first record otherwise
+    if records.is_empty() {
+        writer.write_record(AccountRecord::HEADERS)?;
+    }
+
+    for record in records {
+        writer.serialize(CsvAccountRecord::new(record, bool_format))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+// Label of the totals row, in the client column
+const TOTALS_ROW_LABEL: &str = "total";
+
+// Append a row with the sums of the available, held and total balances of the records, and the number of locked
+// accounts in the locked column. In minor units when a scale is given, like the account rows.
+pub fn write_totals_row<W: Write>(
+    records: &[AccountRecord],
+    minor_units_scale: Option<u32>,
+    writer: &mut Writer<W>,
+) -> Result<(), EngineError> {
+    let sum = |balance: fn(&AccountRecord) -> Decimal| -> Result<String, EngineError> {
+        let sum = records.iter().map(balance).sum::<Decimal>();
+        match minor_units_scale {
+            // Exact as soon as every balance is, which the account rows already checked
+            Some(scale) => to_minor_units(sum, scale).map(|minor_units| minor_units.to_string()).ok_or_else(|| {
+                EngineError::AccountError(format!("total {} is not a whole number of minor units at scale {}", sum, scale))
+            }),
+            None => Ok(sum.to_string()),
+        }
+    };
+    let locked_count = records.iter().filter(|record| record.locked).count();
+
+    writer.write_record([
+        TOTALS_ROW_LABEL.to_string(),
+        sum(|record| record.available)?,
+        sum(|record| record.held)?,
+        sum(|record| record.total)?,
+        locked_count.to_string(),
+    ])?;
+    writer.flush()?;
+    Ok(())
 }
 
+// Write balances as CSV in integer amounts of minor units, failing on a balance with more decimal places than scale
+pub fn write_account_records_in_minor_units<W: Write>(
+    records: &[AccountRecord],
+    scale: u32,
+    bool_format: BoolFormat,
+    writer: &mut Writer<W>,
+) -> Result<(), EngineError> {
+    if records.is_empty() {
+        writer.write_record(MinorUnitsAccountRecord::HEADERS)?;
+    }
+
+    for record in records {
+        writer.serialize(MinorUnitsAccountRecord::new(record, scale, bool_format)?)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+impl Default for TransactionEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionEngine {
+    // An engine with the default configuration, use the builder to configure it
+    pub fn new() -> Self {
+        TransactionEngineBuilder::new().build()
+    }
+
+    pub fn builder() -> TransactionEngineBuilder {
+        TransactionEngineBuilder::new()
+    }
+
+
+    fn overdraft_limit_for(&self, client: u16) -> Decimal {
+        self.config.client_overdraft_limits.get(&client)
+            .copied()
+            .unwrap_or(self.config.overdraft_limit)
+    }
+
+    fn output_precision_for(&self, client: u16) -> u32 {
+        self.accounts.get(&client)
+            .and_then(|account| account.currency.as_ref())
+            .and_then(|currency| self.config.currency_output_precisions.get(currency))
+            .copied()
+            .unwrap_or(self.config.output_precision)
+    }
+
+    // CSV reader of transactions with the configured trimming, ready for process_transactions_from_reader
+    pub fn input_reader<R: Read>(&self, reader: R) -> Reader<R> {
+        self.input_reader_builder().from_reader(reader)
+    }
+
+    // Builder of the input_reader, for inputs needing more reader options
+    pub fn input_reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.trim(self.config.trim);
+        builder
+    }
+
+    // Process CSV transactions held in memory as a whole input, read like the input files. Calls finish, so the
+    // transactions buffered in chronological order or deferred are applied or rejected before returning.
+    pub fn process_bytes(&mut self, data: &[u8]) -> Result<(), EngineError> {
+        let mut reader = self.input_reader(data);
+        self.process_transactions_from_reader(&mut reader)?;
+        self.finish()
+    }
+
+    pub fn process_transactions_from_reader<R: Read>(&mut self, reader: &mut Reader<R>) -> Result<(), EngineError> {
+        let headers = reader.headers()?.clone();
+        let Some(amount_index) = self.start_input(&headers)? else {
+            return Ok(());
+        };
+
+        let mut records = reader.records().enumerate().peekable();
+        while let Some((row, result)) = records.next() {
+            if self.should_stop() {
+                break;
+            }
+            let record = match result {
+                Err(err) if self.config.allow_truncated && records.peek().is_none() => {
+                    self.config.error_format.log(format_args!("Ignoring truncated last row: {}", err));
+                    break;
+                }
+                result => result?,
+            };
+            self.process_record(row, record, &headers, amount_index)?;
+        }
+
+        Ok(())
+    }
+
+    // Same as process_transactions_from_reader, yielding to the runtime while waiting for input.
+    // Processing the transactions themselves doesn't yield, nor does waiting for the control file.
+    #[cfg(feature = "async")]
+    pub async fn process_transactions_from_async_reader<R: tokio::io::AsyncRead + Unpin + Send>(
+        &mut self,
+        reader: &mut csv_async::AsyncReader<R>,
+    ) -> Result<(), EngineError> {
+        let headers = StringRecord::from_iter(reader.headers().await?);
+        let Some(amount_index) = self.start_input(&headers)? else {
+            return Ok(());
+        };
+
+        let mut async_record = csv_async::StringRecord::new();
+        let mut row = 0;
+        while !self.should_stop() && reader.read_record(&mut async_record).await? {
+            let mut record = StringRecord::from_iter(&async_record);
+            record.set_position(async_record.position().map(|async_position| {
+                let mut position = csv::Position::new();
+                position.set_byte(async_position.byte())
+                    .set_line(async_position.line())
+                    .set_record(async_position.record());
+                position
+            }));
+            self.process_record(row, record, &headers, amount_index)?;
+            row += 1;
+        }
+
+        Ok(())
+    }
+
+    // Check the headers of an input fed one record at a time with process_input_record, such as a server
+    // connection. False if it has no headers, and so no records either.
+    pub fn start_record_input(&mut self, headers: &StringRecord) -> Result<bool, EngineError> {
+        Ok(self.start_input(headers)?.is_some())
+    }
+
+    // Process a record of an input started with start_record_input, row being its index in that input
+    pub fn process_input_record(&mut self, row: usize, record: StringRecord, headers: &StringRecord)
+        -> Result<(), EngineError> {
+        let amount_index = headers.iter().position(|header| header == "amount");
+        self.process_record(row, record, headers, amount_index)
+    }
+
+    // Check the headers of a new input, returning the index of its amount column if it has any rows to process
+    fn start_input(&mut self, headers: &StringRecord) -> Result<Option<Option<usize>>, EngineError> {
+        // A totally empty input has no header to check, nor any transaction
+        if headers.is_empty() {
+            return Ok(None);
+        }
+        self.check_schema(headers)?;
+        Ok(Some(headers.iter().position(|header| header == "amount")))
+    }
+
+    fn process_record(
+        &mut self,
+        row: usize,
+        mut record: StringRecord,
+        headers: &StringRecord,
+        amount_index: Option<usize>,
+    ) -> Result<(), EngineError> {
+        if row.is_multiple_of(CONTROL_FILE_CHECK_ROWS) && let Some((path, poll_interval)) = &self.config.control_file {
+            wait_for_control_file_removal(path, *poll_interval, self.config.error_format);
+        }
+
+        if self.config.lenient_amounts && let Some(index) = amount_index {
+            record = transaction::strip_amount_trailing_bytes(&record, index);
+        }
+        if self.config.thousands_separators && let Some(index) = amount_index {
+            record = transaction::strip_thousands_separators(&record, index);
+        }
+
+        let transaction: Transaction = record.deserialize(Some(headers))
+            .map_err(|err| id_range_error(&record, headers).unwrap_or(err.into()))?;
+        self.processed_count += 1;
+        if self.config.progress_interval.is_some_and(|rows| self.processed_count.is_multiple_of(rows)) {
+            self.config.error_format.log(format_args!("Processed {} rows", self.processed_count));
+        }
+        if self.config.chronological {
+            self.buffer_transaction(transaction)
+        } else {
+            self.process_transaction_until_stop(transaction)
+        }
+    }
+
+    // Process input files known to hold disjoint sets of clients in parallel, each in its own engine with the same
+    // configuration, callbacks and starting state, then merge their changes into this one. A client changed by
+    // several files is an error.
+    pub fn process_disjoint_files<P: AsRef<Path> + Sync>(&mut self, paths: &[P]) -> Result<(), EngineError> {
+        let callbacks = SharedCallbacks::take(self);
+        let result = self.process_shards(paths, &callbacks);
+        callbacks.restore(self);
+        result
+    }
+
+    fn process_shards<P: AsRef<Path> + Sync>(&mut self, paths: &[P], callbacks: &SharedCallbacks)
+        -> Result<(), EngineError> {
+        let engines = thread::scope(|scope| {
+            let handles: Vec<_> = paths.iter()
+                .map(|path| {
+                    let mut engine = self.shard(callbacks);
+                    scope.spawn(move || -> Result<TransactionEngine, EngineError> {
+                        let mut reader = engine.input_reader(open_input_file(path)?);
+                        engine.process_transactions_from_reader(&mut reader)?;
+                        engine.finish()?;
+                        Ok(engine)
+                    })
+                })
+                .collect();
+
+            handles.into_iter()
+                .map(|handle| handle.join().expect("input file processing thread panicked"))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let changes: Vec<_> = engines.into_iter().map(|engine| engine.changes_since(self)).collect();
+        let mut changed_clients = HashSet::new();
+        let mut new_transactions = HashSet::new();
+        for engine in &changes {
+            if let Some(&client) = engine.accounts.keys().filter(|client| !changed_clients.insert(**client)).min() {
+                return Err(EngineError::OverlappingClients(
+                    format!("client {} has transactions in more than one input file", client),
+                ));
+            }
+            if let Some(&tx) = engine.transaction_history.keys().filter(|tx| !new_transactions.insert(**tx)).min() {
+                return Err(EngineError::OverlappingClients(
+                    format!("transaction {} is in more than one input file", tx),
+                ));
+            }
+        }
+
+        for engine in changes {
+            self.transaction_history.retain(|tx, _| !engine.pruned_transactions.contains(tx));
+            self.history_order.retain(|tx| !engine.pruned_transactions.contains(tx));
+            self.absorb(engine);
+        }
+        Ok(())
+    }
+
+    // Engine for one of the files processed in parallel, starting from the accounts and history of this one
+    fn shard(&self, callbacks: &SharedCallbacks) -> TransactionEngine {
+        let mut engine = self.config.clone().build();
+        engine.accounts = self.accounts.clone();
+        engine.transaction_history = self.transaction_history.clone();
+        engine.rejected_transactions = self.rejected_transactions.clone();
+        engine.history_order = self.history_order.clone();
+        engine.pruned_transactions = self.pruned_transactions.clone();
+        engine.last_tx = self.last_tx;
+        engine.schema = self.schema.clone();
+        callbacks.install(&mut engine);
+        engine
+    }
+
+    // Keep only what changed since the state a shard started from: the accounts that differ from it, and the
+    // transactions recorded, rejected or pruned since
+    fn changes_since(mut self, base: &TransactionEngine) -> TransactionEngine {
+        self.accounts.retain(|client, account| base.accounts.get(client) != Some(account));
+        self.transaction_history.retain(|tx, _| !base.transaction_history.contains_key(tx));
+        self.rejected_transactions.retain(|tx, _| !base.rejected_transactions.contains_key(tx));
+        self.history_order.retain(|tx| !base.transaction_history.contains_key(tx));
+        self.pruned_transactions.retain(|tx| !base.pruned_transactions.contains(tx));
+        self
+    }
+
+    // Combine the results of an engine that processed another shard of the input into this one.
+    // Shards must be disjoint: a client or a tx id found in both engines is an error, and nothing is merged.
+    pub fn merge(&mut self, other: TransactionEngine) -> Result<(), EngineError> {
+        if let Some(client) = self.overlapping_client(&other) {
+            return Err(EngineError::OverlappingClients(
+                format!("client {} has an account in both engines", client),
+            ));
+        }
+        // Pruned transactions were processed as well, only their details were dropped
+        let processed = |engine: &TransactionEngine, tx: &u32| {
+            engine.transaction_history.contains_key(tx) || engine.pruned_transactions.contains(tx)
+        };
+        if let Some(tx) = other.transaction_history.keys().chain(&other.pruned_transactions)
+            .filter(|tx| processed(self, tx))
+            .min()
+            .copied() {
+            let message = format!("Cannot merge transaction {}: it was processed by both engines", tx);
+            let client = other.transaction_history.get(&tx).or(self.transaction_history.get(&tx))
+                .map(|transaction| transaction.client);
+            return Err(match client {
+                Some(client) => EngineError::invalid_transaction_of(tx, client, message),
+                None => EngineError::invalid_transaction(message),
+            });
+        }
+
+        self.absorb(other);
+        Ok(())
+    }
+
+    // Add the state of another engine to this one, its accounts replacing those of the same clients. The
+    // transactions it buffered until finish() are buffered in this one instead.
+    fn absorb(&mut self, other: TransactionEngine) {
+        self.accounts.extend(other.accounts);
+        self.transaction_history.extend(other.transaction_history);
+        self.rejected_transactions.extend(other.rejected_transactions);
+        self.history_order.extend(other.history_order);
+        self.pruned_transactions.extend(other.pruned_transactions);
+        self.pending_transactions.extend(other.pending_transactions);
+        self.deferred_transactions.extend(other.deferred_transactions);
+        self.lock_changelog.extend(other.lock_changelog);
+        self.timeseries.extend(other.timeseries);
+        self.ledger.extend(other.ledger);
+        self.processed_count += other.processed_count;
+        self.applied_count += other.applied_count;
+        self.rejected_count += other.rejected_count;
+        self.collected_fees += other.collected_fees;
+        self.last_tx = self.last_tx.max(other.last_tx);
+    }
+
+    fn overlapping_client(&self, other: &TransactionEngine) -> Option<u16> {
+        other.accounts.keys().filter(|client| self.accounts.contains_key(client)).min().copied()
+    }
+
+    // Apply the transactions buffered in chronological mode and reject the deferred disputes still unmatched,
+    // to be called once all the input was read
+    pub fn finish(&mut self) -> Result<(), EngineError> {
+        let mut pending_transactions = std::mem::take(&mut self.pending_transactions);
+
+        // Transactions with the same timestamp are ordered by sequence number, their input order by default
+        pending_transactions.sort_by_key(|(sequence, transaction)| (transaction.timestamp, *sequence));
+        for (_, transaction) in pending_transactions {
+            if self.should_stop() {
+                break;
+            }
+            self.process_transaction_until_stop(transaction)?;
+        }
+
+        for transaction in std::mem::take(&mut self.deferred_transactions) {
+            let err = EngineError::invalid_transaction_of(transaction.tx, transaction.client, format!(
+                "Cannot {} non-existent transaction: {}", transaction.transaction_type.as_str(), transaction.tx,
+            ));
+            self.reject(&transaction, err)?;
+        }
+
+        Ok(())
+    }
+
+    // Diagnostics only written in verbose mode
+    fn debug_log(&self, message: impl fmt::Display) {
+        if self.config.verbose {
+            self.config.error_format.log(message);
+        }
+    }
+
+    fn should_stop(&self) -> bool {
+        self.stopped || self.config.stop_flag.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    fn process_transaction_until_stop(&mut self, transaction: Transaction) -> Result<(), EngineError> {
+        let tx = transaction.tx;
+        self.process_transaction(transaction)?;
+        self.stopped = self.config.stop_after_tx == Some(tx);
+        Ok(())
+    }
+
+    fn buffer_transaction(&mut self, transaction: Transaction) -> Result<(), EngineError> {
+        if transaction.timestamp.is_none() {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Transaction {} has no timestamp, which is required to sort transactions", transaction.tx),
+            ));
+        }
+
+        let SequenceSource(sequence) = &mut self.sequence;
+        self.pending_transactions.push((sequence.next(), transaction));
+        Ok(())
+    }
+
+    // Write the engine state (accounts, disputes and transaction history) as JSON, configuration is not included
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> Result<(), EngineError> {
+        let snapshot = EngineSnapshot::new(&self.accounts, &self.transaction_history, &self.rejected_transactions,
+            &self.history_order, &self.pruned_transactions, self.last_tx, &self.deferred_transactions);
+        serde_json::to_writer(writer, &snapshot)?;
+        Ok(())
+    }
+
+    // Replace the engine state with a snapshot previously written by save_snapshot, keeping the configuration
+    pub fn load_snapshot<R: Read>(&mut self, reader: R) -> Result<(), EngineError> {
+        let state = serde_json::from_reader::<_, EngineSnapshot>(reader)?.into_state();
+        self.accounts = state.accounts;
+        self.transaction_history = state.transaction_history;
+        self.rejected_transactions = state.rejected_transactions;
+        self.pruned_transactions = state.pruned_transactions;
+        self.history_order = state.history_order;
+        self.last_tx = state.last_tx;
+        self.deferred_transactions = state.deferred_transactions;
+        if self.config.history_window.is_some() && self.history_order.is_empty() {
+            // Saved without a history window, the insertion order is unknown and taken as the tx id order
+            let mut history_order: Vec<_> = self.transaction_history.keys().copied().collect();
+            history_order.sort();
+            self.history_order = history_order.into();
+        } else if self.config.history_window.is_none() {
+            self.history_order.clear();
+        }
+        Ok(())
+    }
+
+    // Set opening balances, this is meant to be called before processing any transaction
+    pub fn seed_balances_from_reader<R: Read>(&mut self, reader: &mut Reader<R>) -> Result<(), EngineError> {
+        let mut seeded_clients = HashSet::new();
+
+        for result in reader.deserialize() {
+            let seed: SeedBalance = result?;
+            if self.config.max_accounts.is_some_and(|max_accounts| self.accounts.len() >= max_accounts)
+                && !self.accounts.contains_key(&seed.client) {
+                return Err(EngineError::InvalidSeedBalance(
+                    format!("Seed balance for client {} exceeds the account limit", seed.client),
+                ));
+            }
+            let AccountFactory(factory) = &self.account_factory;
+            let account = self.accounts.entry(seed.client).or_insert_with(|| factory(seed.client));
+
+            if seeded_clients.insert(seed.client) {
+                account.available = seed.balance;
+                account.total = seed.balance;
+                continue;
+            }
+
+            match self.config.seed_duplicate_policy {
+                SeedDuplicatePolicy::Error => {
+                    return Err(EngineError::InvalidSeedBalance(
+                        format!("Duplicate seed balance for client {}", seed.client),
+                    ));
+                }
+                SeedDuplicatePolicy::Sum => {
+                    account.available += seed.balance;
+                    account.total += seed.balance;
+                }
+                SeedDuplicatePolicy::LastWins => {
+                    account.available = seed.balance;
+                    account.total = seed.balance;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_schema(&mut self, headers: &StringRecord) -> Result<(), EngineError> {
+        match &self.schema {
+            None => self.schema = Some(headers.clone()),
+            Some(schema) if schema != headers && !self.config.allow_schema_drift => {
+                return Err(EngineError::SchemaDrift(format!(
+                    "expected headers [{}] but found [{}]",
+                    schema.iter().collect::<Vec<_>>().join(","),
+                    headers.iter().collect::<Vec<_>>().join(","),
+                )));
+            }
+            Some(_) => {}
+        }
+
+        Ok(())
+    }
+
+    fn process_transaction(&mut self, mut transaction: Transaction) -> Result<(), EngineError> {
+        if self.config.signed_amounts
+            && let Some(amount) = transaction.amount
+            && amount < Decimal::ZERO {
+            transaction.transaction_type = match transaction.transaction_type {
+                TransactionType::Deposit => TransactionType::Withdrawal,
+                TransactionType::Withdrawal => TransactionType::Deposit,
+                // Rejected by the validation, dispute related transactions have no amount
+                other => other,
+            };
+            transaction.amount = Some(-amount);
+        }
+
+        if self.config.enabled_types.as_ref().is_some_and(|types| !types.contains(&transaction.transaction_type)) {
+            self.debug_log(format_args!("Skipping transaction {}: {} transactions are disabled",
+                transaction.tx, transaction.transaction_type.as_str()));
+            return Ok(());
+        }
+
+        // Reprocessing the same input is a no-op for deposits and withdrawals
+        if self.is_replay(&transaction) {
+            self.config.error_format.log(format_args!("Ignoring already processed transaction {}", transaction.tx));
+            return Ok(());
+        }
+
+        // Validate transaction
+        self.validate_transaction(&transaction)?;
+        if transaction.requires_amount() {
+            self.last_tx = self.last_tx.max(Some(transaction.tx));
+        }
+
+        // Check the rounded amount as well, a positive amount below the internal precision rounds to zero
+        let amount = transaction.amount;
+        let transaction = self.with_internal_precision_amount(transaction);
+        if let Some(amount) = amount && transaction.amount == Some(Decimal::ZERO) {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client, format!(
+                "Transaction amount {} rounds to zero at the internal precision", amount,
+            )));
+        }
+
+        if self.config.deferred_disputes
+            && matches!(transaction.transaction_type,
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback)
+            && !self.transaction_history.contains_key(&transaction.tx)
+            && !self.pruned_transactions.contains(&transaction.tx) {
+            self.deferred_transactions.push(transaction);
+            return Ok(());
+        }
+
+        let was_locked = self.is_locked(transaction.client);
+
+        let res = self.check_currency(&transaction).and_then(|()| match transaction.transaction_type {
+            TransactionType::Deposit => self.process_deposit(&transaction),
+            TransactionType::Withdrawal => self.process_withdrawal(&transaction),
+            TransactionType::Dispute => self.process_dispute(&transaction),
+            TransactionType::Resolve => self.process_resolve(&transaction),
+            TransactionType::Chargeback => self.process_chargeback(&transaction),
+            TransactionType::ResolveAll => self.process_resolve_all(&transaction),
+            TransactionType::Freeze | TransactionType::Unfreeze => self.process_freeze(&transaction),
+        });
+
+        // Catch accounting bugs where they happen rather than in the output
+        if cfg!(debug_assertions) && let Some(account) = self.accounts.get(&transaction.client) {
+            debug_assert!(account.check_invariant().is_ok(),
+                "balance invariant violated by tx {}: {:?}", transaction.tx, account);
+        }
+
+        let locked = self.is_locked(transaction.client);
+        if locked != was_locked {
+            self.lock_changelog.push(LockChange {
+                client: transaction.client,
+                tx: transaction.tx,
+                locked,
+            });
+        }
+
+        if res.is_ok() {
+            self.applied_count += 1;
+        }
+
+        if res.is_ok() && self.config.timeseries_client == Some(transaction.client) {
+            self.record_timeseries(&transaction);
+        }
+
+        if res.is_ok() && self.config.ledger {
+            self.record_ledger_entry(&transaction);
+        }
+
+        if res.is_ok()
+            && let Some(TransactionHook(hook)) = &mut self.transaction_hook
+            && let Some(account) = self.accounts.get(&transaction.client) {
+            hook(&transaction, account);
+        }
+
+        match res {
+            Ok(()) if transaction.requires_amount() => self.retry_deferred(transaction.tx),
+            Ok(()) => Ok(()),
+            Err(e) => self.reject(&transaction, e),
+        }
+    }
+
+    fn reject(&mut self, transaction: &Transaction, e: EngineError) -> Result<(), EngineError> {
+        // Remember rejected deposits and withdrawals so that replaying them is a no-op as well
+        if transaction.requires_amount() {
+            self.rejected_transactions.insert(transaction.tx, transaction.clone());
+        }
+
+        if self.config.strict {
+            return Err(e);
+        }
+
+        // Report the error but continue processing other transactions
+        self.rejected_count += 1;
+        match &mut self.rejection_hook {
+            Some(RejectionHook(hook)) => hook(transaction, &e),
+            None => eprintln!("{}", self.config.error_format.format_rejection(transaction.tx, &e)),
+        }
+        Ok(())
+    }
+
+    // Process the deferred disputes, resolves and chargebacks of a deposit or withdrawal that was just applied,
+    // in input order
+    fn retry_deferred(&mut self, tx: u32) -> Result<(), EngineError> {
+        let (matching, deferred): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred_transactions)
+            .into_iter()
+            .partition(|transaction| transaction.tx == tx);
+        self.deferred_transactions = deferred;
+
+        for transaction in matching {
+            self.process_transaction(transaction)?;
+        }
+        Ok(())
+    }
+
+    fn record_timeseries(&mut self, transaction: &Transaction) {
+        if let Some(account) = self.accounts.get(&transaction.client) {
+            self.timeseries.push(BalanceSnapshot {
+                tx: transaction.tx,
+                transaction_type: transaction.transaction_type.clone(),
+                available: account.available,
+                held: account.held,
+                total: account.total,
+            });
+        }
+    }
+
+    pub fn timeseries(&self) -> &[BalanceSnapshot] {
+        &self.timeseries
+    }
+
+    fn record_ledger_entry(&mut self, transaction: &Transaction) {
+        if let Some(account) = self.accounts.get(&transaction.client) {
+            self.ledger.push(LedgerEntry {
+                tx: transaction.tx,
+                client: transaction.client,
+                transaction_type: transaction.transaction_type.clone(),
+                available_after: account.available,
+                held_after: account.held,
+                total_after: account.total,
+            });
+        }
+    }
+
+    pub fn ledger(&self) -> &[LedgerEntry] {
+        &self.ledger
+    }
+
+    fn with_internal_precision_amount(&self, mut transaction: Transaction) -> Transaction {
+        transaction.amount = transaction.amount.map(|amount| self.at_internal_precision(amount));
+        transaction
+    }
+
+    fn at_internal_precision(&self, amount: Decimal) -> Decimal {
+        match self.config.internal_precision {
+            Some(internal_precision) => amount.round_dp(internal_precision),
+            None => amount,
+        }
+    }
+
+    fn is_replay(&self, transaction: &Transaction) -> bool {
+        // Stored transactions have their amount at the internal precision
+        let transaction = self.with_internal_precision_amount(transaction.clone());
+
+        // The content of pruned transactions is unknown, reusing their id is taken as a replay
+        transaction.requires_amount()
+            && (self.transaction_history.get(&transaction.tx) == Some(&transaction)
+                || self.rejected_transactions.get(&transaction.tx) == Some(&transaction)
+                || self.pruned_transactions.contains(&transaction.tx))
+    }
+
+    // Whether the account rejects new deposits and withdrawals, permanently or until it is unfrozen
+    fn is_locked(&self, client: u16) -> bool {
+        self.accounts.get(&client).is_some_and(|account| account.locked || account.frozen)
+    }
+
+    // Register a hook called after each transaction successfully applied, replacing any previous one
+    pub fn on_transaction_applied(&mut self, hook: impl FnMut(&Transaction, &Account) + Send + 'static) {
+        self.transaction_hook = Some(TransactionHook(Box::new(hook)));
+    }
+
+    // Register a hook called with each rejected transaction instead of logging it, replacing any previous one.
+    // Not called in strict mode, where the rejection is returned as an error instead.
+    pub fn on_transaction_rejected(&mut self, hook: impl FnMut(&Transaction, &EngineError) + Send + 'static) {
+        self.rejection_hook = Some(RejectionHook(Box::new(hook)));
+    }
+
+    // Forget all the accounts, transactions and counters, keeping the configuration, hooks, account factory and
+    // sequence
+    pub fn reset(&mut self) {
+        let mut engine = self.config.clone().build();
+        engine.transaction_hook = self.transaction_hook.take();
+        engine.rejection_hook = self.rejection_hook.take();
+        std::mem::swap(&mut engine.account_factory, &mut self.account_factory);
+        std::mem::swap(&mut engine.sequence, &mut self.sequence);
+        *self = engine;
+    }
+
+    // Order the transactions with the same timestamp in chronological mode with this sequence
+    pub fn order_ties_with(&mut self, sequence: impl Sequence + 'static) {
+        self.sequence = SequenceSource(Box::new(sequence));
+    }
+
+    // Create the accounts of new clients with this factory rather than with empty accounts
+    pub fn create_accounts_with(&mut self, factory: impl Fn(u16) -> Account + Send + 'static) {
+        self.account_factory = AccountFactory(Box::new(factory));
+    }
+
+    pub fn collected_fees(&self) -> Decimal {
+        self.collected_fees
+    }
+
+    // Sum of the held balances of every account. Like total_available, the sum saturates at the bounds of Decimal
+    // instead of overflowing, which only balances close to Decimal::MAX can reach.
+    pub fn total_held(&self) -> Decimal {
+        self.accounts.values().fold(Decimal::ZERO, |total, account| total.saturating_add(account.held))
+    }
+
+    pub fn total_available(&self) -> Decimal {
+        self.accounts.values().fold(Decimal::ZERO, |total, account| total.saturating_add(account.available))
+    }
+
+    pub fn total_locked_count(&self) -> usize {
+        self.accounts.values().filter(|account| account.locked).count()
+    }
+
+    // Client, tx id and held amount of every open dispute, sorted by client then tx id
+    pub fn open_disputes(&self) -> Vec<(u16, u32, Decimal)> {
+        let mut disputes: Vec<_> = self.accounts.values()
+            .flat_map(|account| account.disputed().map(|(tx, held)| (account.client, tx, held)))
+            .collect();
+        disputes.sort_unstable_by_key(|&(client, tx, _)| (client, tx));
+        disputes
+    }
+
+    // Clients currently above the held amount or open disputes thresholds, sorted by client ID. Only reported
+    // for monitoring, their transactions are processed as usual
+    pub fn flagged_clients(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.accounts.values()
+            .filter(|account| {
+                self.config.flagged_held_threshold.is_some_and(|threshold| account.held > threshold)
+                    || self.config.flagged_disputes_threshold
+                        .is_some_and(|threshold| account.disputed_transactions.len() > threshold)
+            })
+            .map(|account| account.client)
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    // Every client with an account, sorted by client ID, whatever their balances
+    pub fn client_ids(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    // Format of the diagnostics, for callers logging alongside the engine
+    pub fn error_format(&self) -> ErrorFormat {
+        self.config.error_format
+    }
+
+    pub fn stats(&self) -> ProcessingStats {
+        ProcessingStats {
+            processed: self.processed_count,
+            applied: self.applied_count,
+            rejected: self.rejected_count,
+            accounts: self.accounts.len(),
+        }
+    }
+
+    pub fn lock_changelog(&self) -> &[LockChange] {
+        &self.lock_changelog
+    }
+
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), EngineError> {
+        transaction.validate()?;
+
+        // Check for implausible amounts
+        if let (Some(amount), Some(max_amount)) = (transaction.amount, self.config.max_transaction_amount)
+            && amount > max_amount {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Transaction amount {} is above the maximum of {}", amount, max_amount),
+            ));
+        }
+
+        // Check for dust deposits
+        if transaction.transaction_type == TransactionType::Deposit
+            && let Some(amount) = transaction.amount
+            && amount < self.config.min_deposit_amount {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Deposit amount {} is below the minimum of {}", amount, self.config.min_deposit_amount),
+            ));
+        }
+
+        // Check for excessive precision
+        if self.config.validate_precision
+            && let Some(amount) = transaction.amount
+            && amount.scale() > MAX_INPUT_SCALE {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Transaction amount {} has more than {} decimal places", amount, MAX_INPUT_SCALE),
+            ));
+        }
+
+        // Check for duplicate transaction IDs for deposit/withdrawal
+        if matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal)
+            && self.transaction_history.contains_key(&transaction.tx) {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Duplicate transaction ID: {}", transaction.tx),
+            ));
+        }
+
+        // Check for tx ids going backwards, a sign of a corrupted feed when they are meant to increase
+        if self.config.monotonic_tx_ids
+            && transaction.requires_amount()
+            && let Some(last_tx) = self.last_tx
+            && transaction.tx <= last_tx {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client,
+                format!("Transaction ID {} is not above the previous transaction ID {}", transaction.tx, last_tx),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Currencies are never mixed within an account, transactions without a currency match any account
+    fn check_currency(&self, transaction: &Transaction) -> Result<(), EngineError> {
+        let account_currency = self.accounts.get(&transaction.client)
+            .and_then(|account| account.currency.as_ref());
+
+        if let (Some(currency), Some(account_currency)) = (&transaction.currency, account_currency)
+            && currency != account_currency {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client, "currency mismatch"));
+        }
+        Ok(())
+    }
+
+    fn check_account_exists(&self, transaction: &Transaction) -> Result<(), EngineError> {
+        let client = transaction.client;
+        if self.config.require_existing_accounts
+            && !self.accounts.contains_key(&client)
+            && !self.config.provisioned_clients.contains(&client) {
+            return Err(EngineError::invalid_transaction_of(
+                transaction.tx, client, format!("Client {} has no provisioned account", client),
+            ));
+        }
+        if self.config.max_accounts.is_some_and(|max_accounts| self.accounts.len() >= max_accounts)
+            && !self.accounts.contains_key(&client) {
+            return Err(EngineError::invalid_transaction_of(transaction.tx, client, "account limit exceeded"));
+        }
+        Ok(())
+    }
+
+    // Get the account of the transaction's client, creating it if needed, with the transaction's currency
+    fn account_for(&mut self, transaction: &Transaction) -> &mut Account {
+        let AccountFactory(factory) = &self.account_factory;
+        let account = self.accounts.entry(transaction.client).or_insert_with(|| factory(transaction.client));
+        if account.currency.is_none() {
+            account.currency = transaction.currency.clone();
+        }
+        account
+    }
+
+    fn process_deposit(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.check_account_exists(transaction)?;
+        let amount = transaction.required_amount()?;
+        let account = self.account_for(transaction);
+        
+        account.deposit(amount)
+            .map_err(|err| operation_error(err, "deposit", transaction))?;
+        
+        // Store transaction for potential disputes
+        self.record_history(transaction);
+        Ok(())
+    }
+
+    fn process_withdrawal(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.check_account_exists(transaction)?;
+        let amount = transaction.required_amount()?;
+        // Rounded like the amounts, so that balances stay at the internal precision
+        let fee = self.at_internal_precision(
+            self.config.withdrawal_fee + amount * self.config.withdrawal_fee_percentage / Decimal::ONE_HUNDRED,
+        );
+        let overdraft_limit = self.overdraft_limit_for(transaction.client);
+        let lock_threshold = self.config.failed_withdrawals_lock_threshold;
+        let error_format = self.config.error_format;
+        let account = self.account_for(transaction);
+        
+        // The fee is part of the funds that must be available
+        let result = account.withdraw_with_overdraft(amount + fee, overdraft_limit);
+        if let Err(AccountError::InsufficientFunds) = result
+            && lock_threshold.is_some_and(|threshold| account.failed_withdrawals >= threshold) {
+            error_format.log(format_args!("Locking account {} after {} failed withdrawals in a row",
+                transaction.client, account.failed_withdrawals));
+            account.locked = true;
+        }
+        result.map_err(|err| operation_error(err, "withdraw", transaction))?;
+
+        if fee > Decimal::ZERO {
+            self.config.error_format.log(format_args!("Charged fee {} on withdrawal {}", fee, transaction.tx));
+            self.collected_fees += fee;
+        }
+        
+        // Store transaction for potential disputes
+        self.record_history(transaction);
+        Ok(())
+    }
+
+    fn record_history(&mut self, transaction: &Transaction) {
+        self.transaction_history.insert(transaction.tx, transaction.clone());
+
+        let Some(history_window) = self.config.history_window else {
+            return;
+        };
+        self.history_order.push_back(transaction.tx);
+
+        for _ in history_window..self.history_order.len() {
+            let Some(tx) = self.history_order.pop_front() else {
+                break;
+            };
+
+            // A disputed transaction goes back at the end of the window, to be pruned after its dispute
+            if self.is_disputed(tx) {
+                self.history_order.push_back(tx);
+            } else {
+                self.transaction_history.remove(&tx);
+                self.pruned_transactions.insert(tx);
+            }
+        }
+    }
+
+    fn is_disputed(&self, tx: u32) -> bool {
+        self.transaction_history.get(&tx)
+            .and_then(|transaction| self.accounts.get(&transaction.client))
+            .is_some_and(|account| account.disputed_transactions.contains_key(&tx))
+    }
+
+    // Find the original transaction referenced by a dispute, resolve or chargeback, and check it can be disputed
+    fn lookup_disputable(&self, tx: u32, client: u16, action: &str) -> Result<&Transaction, EngineError> {
+        if self.pruned_transactions.contains(&tx) {
+            return Err(EngineError::invalid_transaction_of(tx, client,
+                format!("Cannot {} transaction {}: it is out of the history window", action, tx)
+            ));
+        }
+
+        let original_transaction = self.transaction_history.get(&tx)
+            .ok_or_else(|| EngineError::invalid_transaction_of(tx, client,
+                format!("Cannot {} non-existent transaction: {}", action, tx)
+            ))?;
+
+        // Verify client matches
+        if original_transaction.client != client {
+            return Err(EngineError::invalid_transaction_of(tx, client,
+                format!("Cannot {} transaction from different client", action),
+            ));
+        }
+
+        if !self.config.disputable_types.contains(&original_transaction.transaction_type) {
+            let mut disputable: Vec<&str> = self.config.disputable_types.iter().map(TransactionType::as_str).collect();
+            disputable.sort_unstable();
+            return Err(EngineError::invalid_transaction_of(tx, client,
+                format!("Cannot {} transaction {}: it is a {}, only {} transactions can be disputed",
+                        action, tx, original_transaction.transaction_type.as_str(), disputable.join(" and ")),
+            ));
+        }
+
+        Ok(original_transaction)
+    }
+
+    fn process_dispute(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let original_transaction = self.lookup_disputable(transaction.tx, transaction.client, "dispute")?;
+
+        let original_amount = original_transaction.required_amount()?;
+        let is_withdrawal = original_transaction.transaction_type == TransactionType::Withdrawal;
+        // Validation rejects zero amounts, but holding nothing would only leave a useless disputed entry
+        if original_amount == Decimal::ZERO {
+            self.config.error_format.log(format_args!(
+                "Warning: not disputing transaction {} with a zero amount", transaction.tx));
+            return Ok(());
+        }
+
+        let account = self.accounts.get_mut(&transaction.client)
+            .ok_or_else(|| account_not_found(transaction))?;
+
+        // A dispute with an amount only disputes that portion of the transaction, several portions can be disputed
+        let disputed_amount = transaction.amount.unwrap_or(original_amount);
+        let result = match transaction.amount {
+            Some(amount) => {
+                let disputed = account.disputed_amount(transaction.tx);
+                let result = if is_withdrawal {
+                    account.dispute_withdrawal_portion(amount, original_amount, transaction.tx).map(|()| amount)
+                } else {
+                    account.dispute_portion(amount, original_amount, transaction.tx, !self.config.strict_disputes)
+                };
+                if let Err(AccountError::DisputeExceedsTransaction) = result {
+                    return Err(EngineError::invalid_transaction_of(transaction.tx, transaction.client, format!(
+                        "Cannot dispute {} of transaction {}: only {} of its amount {} is not disputed yet",
+                        amount, transaction.tx, original_amount - disputed, original_amount,
+                    )));
+                }
+                result
+            }
+            None if is_withdrawal => account.dispute_withdrawal(original_amount, transaction.tx, false)
+                .map(|()| original_amount),
+            None => account.dispute_with_partial_hold(original_amount, transaction.tx, !self.config.strict_disputes),
+        };
+        let held = result.map_err(|err| operation_error(err, "dispute", transaction))?;
+        if held < disputed_amount {
+            self.config.error_format.log(format_args!(
+                "Disputing transaction {} with not enough balance available, holding amount {} instead",
+                transaction.tx, held));
+        }
+        Ok(())
+    }
+
+    fn process_resolve(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.lookup_disputable(transaction.tx, transaction.client, "resolve")?;
+
+        let account = self.accounts.get_mut(&transaction.client)
+            .ok_or_else(|| account_not_found(transaction))?;
+
+        match account.resolve(transaction.tx) {
+            Ok(settlement) => {
+                self.log_settlement(settlement, "resolve", transaction);
+                Ok(())
+            }
+            Err(AccountError::TransactionNotDisputed) if self.config.lenient_resolve => {
+                self.debug_log(format_args!("Transaction {} is not disputed, ignoring resolve", transaction.tx));
+                Ok(())
+            }
+            Err(err) => Err(operation_error(err, "resolve", transaction)),
+        }
+    }
+
+    fn process_chargeback(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.lookup_disputable(transaction.tx, transaction.client, "chargeback")?;
+
+        let account = self.accounts.get_mut(&transaction.client)
+            .ok_or_else(|| account_not_found(transaction))?;
+
+        let settlement = account.chargeback(transaction.tx)?;
+        self.log_settlement(settlement, "chargeback", transaction);
+        Ok(())
+    }
+
+    fn log_settlement(&self, settlement: Settlement, operation: &str, transaction: &Transaction) {
+        if settlement == Settlement::AlreadyResolved {
+            self.debug_log(format_args!("Transaction {} was already resolved, ignoring {}", transaction.tx, operation));
+        }
+    }
+
+    fn process_resolve_all(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let account = self.accounts.get_mut(&transaction.client)
+            .ok_or_else(|| account_not_found(transaction))?;
+
+        account.resolve_all()
+            .map_err(|err| operation_error(err, "resolve all disputes of", transaction))?;
+        Ok(())
+    }
+
+    fn process_freeze(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let account = self.accounts.get_mut(&transaction.client)
+            .ok_or_else(|| account_not_found(transaction))?;
+
+        if transaction.transaction_type == TransactionType::Freeze {
+            account.freeze();
+        } else {
+            account.unfreeze();
+        }
+        Ok(())
+    }
+
+    // The balances of a client as a JSON object, rounded to its output precision like the CSV output
+    pub fn account_json(&self, client: u16) -> Option<String> {
+        let account = self.accounts.get(&client)?;
+        serde_json::to_string(&account.to_record(self.output_precision_for(client))).ok()
+    }
+
+    // Balances of every account as written in the output, in the configured order. They are exact when written
+    // in minor units, to be converted from the actual balances rather than rounded ones.
+    pub fn account_records(&self) -> Vec<AccountRecord> {
+        let mut sorted_accounts: Vec<_> = self.accounts.values().collect();
+        // Client ids are unique, so an unstable sort still gives a deterministic order
+        match self.config.output_sort {
+            OutputSort::Client => sorted_accounts.sort_unstable_by_key(|account| account.client),
+            OutputSort::Total => sorted_accounts.sort_unstable_by_key(|account| (Reverse(account.total), account.client)),
+        }
+
+        sorted_accounts.into_iter()
+            .map(|account| match self.config.minor_units_scale {
+                Some(_) => account.to_exact_record(),
+                None => account.to_record(self.output_precision_for(account.client)),
+            })
+            .collect()
+    }
+
+    pub fn output_account_balances_to_writer<W: Write>(&mut self, writer: &mut Writer<W>) -> Result<(), EngineError> {
+        match self.config.minor_units_scale {
+            Some(scale) => write_account_records_in_minor_units(&self.account_records(), scale, self.config.bool_format, writer),
+            None => write_account_records(&self.account_records(), self.config.bool_format, writer),
+        }
+    }
+
+    pub fn output_timeseries_to_writer<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), EngineError> {
+        // Snapshots are only recorded for the timeseries client, so they all have its precision
+        if let Some(client) = self.config.timeseries_client {
+            let precision = self.output_precision_for(client);
+            for snapshot in &self.timeseries {
+                writer.serialize(snapshot.rounded(precision))?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn output_ledger_to_writer<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), EngineError> {
+        for entry in &self.ledger {
+            writer.serialize(entry.rounded(self.output_precision_for(entry.client)))?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn output_open_disputes_to_writer<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), EngineError> {
+        writer.write_record(["client", "tx", "held"])?;
+        for (client, tx, held) in self.open_disputes() {
+            writer.serialize((client, tx, held.round_dp(self.output_precision_for(client))))?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn output_lock_changelog_to_writer<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), EngineError> {
+        for change in &self.lock_changelog {
+            writer.serialize(change)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+
+// Process every transaction of the reader with a default engine, and return the final accounts by client ID
+pub fn process_to_accounts<R: Read>(reader: &mut Reader<R>) -> Result<BTreeMap<u16, Account>, EngineError> {
+    let mut engine = TransactionEngine::new();
+    engine.process_transactions_from_reader(reader)?;
+    engine.finish()?;
+
+    Ok(engine.accounts.into_iter().collect())
+}
+
+// Error of a transaction whose client has no account to apply it to
+fn account_not_found(transaction: &Transaction) -> EngineError {
+    EngineError::AccountNotFound { client: transaction.client, tx: transaction.tx }
+}
+
+// Name the operation in locked account errors, so that they can be told apart in the logs
+fn operation_error(err: AccountError, operation: &str, transaction: &Transaction) -> EngineError {
+    match err {
+        AccountError::AccountLocked => EngineError::AccountError(
+            format!("cannot {} tx {}: account {} is locked", operation, transaction.tx, transaction.client),
+        ),
+        AccountError::AccountFrozen => EngineError::AccountError(
+            format!("cannot {} tx {}: account {} is frozen", operation, transaction.tx, transaction.client),
+        ),
+        err => err.into(),
+    }
+}
+
+// Report a client or tx id too large for its type, rather than the generic deserialization error
+fn id_range_error(record: &StringRecord, headers: &StringRecord) -> Option<EngineError> {
+    [("client", u64::from(u16::MAX)), ("tx", u64::from(u32::MAX))].into_iter().find_map(|(column, max)| {
+        let field = record.get(headers.iter().position(|header| header == column)?)?;
+        let is_integer = !field.is_empty() && field.bytes().all(|byte| byte.is_ascii_digit());
+
+        (is_integer && field.parse::<u64>().map_or(true, |id| id > max)).then(|| EngineError::invalid_transaction(
+            format!("{} id {} out of range (max {})", column, field, max),
+        ))
+    })
+}
+
+fn wait_for_control_file_removal(path: &Path, poll_interval: Duration, error_format: ErrorFormat) {
+    if !path.exists() {
+        return;
+    }
+
+    error_format.log(format_args!("Control file {} present, pausing processing", path.display()));
+    while path.exists() {
+        thread::sleep(poll_interval);
+    }
+    error_format.log(format_args!("Control file {} removed, resuming processing", path.display()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn process(engine: &mut TransactionEngine, data: &str) -> Result<(), EngineError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+        engine.process_transactions_from_reader(&mut reader)
+    }
+
+    fn balances(engine: &TransactionEngine) -> Vec<(u16, Decimal, Decimal, Decimal, bool)> {
+        let mut balances: Vec<_> = engine.accounts.values()
+            .map(|account| (account.client, account.available, account.held, account.total, account.locked))
+            .collect();
+        balances.sort();
+        balances
+    }
+
+    #[test]
+    fn test_overdraft_limits() {
+        let mut engine = TransactionEngine::builder()
+            .overdraft_limit(Decimal::from_str("10.0").unwrap())
+            .client_overdraft_limit(2, Decimal::from_str("100.0").unwrap())
+            .build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            withdrawal,1,2,15.0
+            withdrawal,1,3,0.01
+            deposit,2,4,5.0
+            withdrawal,2,5,50.0
+            withdrawal,3,6,10.0
+            withdrawal,3,7,10.0").unwrap();
+
+        // Client 1 is capped by the default limit, client 2 by its own override
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("-10.0").unwrap());
+        assert_eq!(engine.accounts[&2].available, Decimal::from_str("-45.0").unwrap());
+        assert_eq!(engine.accounts[&3].total, Decimal::from_str("-10.0").unwrap());
+    }
+
+    #[test]
+    fn test_precision_validation() {
+        let mut engine = TransactionEngine::builder().precision_validation(true).build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.1234").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("1.1234").unwrap());
+
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,1,2,1.12345");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { .. })));
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("1.1234").unwrap());
+    }
+
+    #[test]
+    fn test_precision_validation_disabled() {
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.12345").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("1.12345").unwrap());
+    }
+
+    #[test]
+    fn test_lock_changelog() {
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,5.0
+            dispute,1,1,
+            chargeback,1,1,
+            dispute,1,2,
+            chargeback,1,2,
+            deposit,2,3,1.0").unwrap();
+
+        // Only the first chargeback changes the locked flag
+        assert_eq!(engine.lock_changelog(), &[LockChange { client: 1, tx: 1, locked: true }]);
+    }
+
+    #[test]
+    fn test_lock_changelog_unlock() {
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            freeze,1,2,
+            freeze,1,3,
+            unfreeze,1,4,
+            deposit,1,5,1.0").unwrap();
+
+        assert_eq!(engine.lock_changelog(), &[
+            LockChange { client: 1, tx: 2, locked: true },
+            LockChange { client: 1, tx: 4, locked: false },
+        ]);
+    }
+
+    #[test]
+    fn test_schema_drift() {
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0").unwrap();
+        let result = process(&mut engine, "client,type,tx,amount
+            1,deposit,2,10.0");
+        assert!(matches!(result, Err(EngineError::SchemaDrift(_))));
+
+        // Same schema is accepted
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,3,10.0").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("20.0").unwrap());
+    }
+
+    #[test]
+    fn test_allow_schema_drift() {
+        let mut engine = TransactionEngine::builder().allow_schema_drift(true).build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0").unwrap();
+        process(&mut engine, "client,type,tx,amount
+            1,deposit,2,10.0").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("20.0").unwrap());
+    }
+
+    #[test]
+    fn test_reprocessing_is_idempotent() {
+        let data = std::fs::read_to_string("tests/data/comprehensive.csv").unwrap();
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, &data).unwrap();
+        let first_run = balances(&engine);
+
+        process(&mut engine, &data).unwrap();
+        assert_eq!(balances(&engine), first_run);
+    }
+
+    #[test]
+    fn test_reprocessing_rejected_withdrawal() {
+        let data = "type,client,tx,amount
+            deposit,1,1,5.0
+            withdrawal,1,2,10.0
+            deposit,1,3,10.0";
+        let mut engine = TransactionEngine::new();
+
+        process(&mut engine, data).unwrap();
+        process(&mut engine, data).unwrap();
+
+        // The rejected withdrawal must not succeed on the second run, now that funds are available
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("15.0").unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_id_with_different_content() {
+        let mut engine = TransactionEngine::new();
+
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            deposit,1,1,6.0");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { .. })));
+    }
+
+    #[test]
+    fn test_exact_duplicate_rows() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+
+        // The whole content of the row is compared, so only an exact duplicate is ignored
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            deposit,1,1,5.0").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("5.0").unwrap());
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,2,1,5.0"), "Invalid transaction: Duplicate transaction ID: 1");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.5"), "Invalid transaction: Duplicate transaction ID: 1");
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("5.0").unwrap());
+    }
+
+    fn seed(engine: &mut TransactionEngine, data: &str) -> Result<(), EngineError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+        engine.seed_balances_from_reader(&mut reader)
+    }
+
+    #[test]
+    fn test_seed_duplicate_policies() {
+        let data = "client,balance
+            1,10.0
+            2,5.0
+            1,2.5";
+
+        let mut engine = TransactionEngine::new();
+        assert!(matches!(seed(&mut engine, data), Err(EngineError::InvalidSeedBalance(_))));
+
+        let mut engine = TransactionEngine::builder().seed_duplicate_policy(SeedDuplicatePolicy::Sum).build();
+        seed(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("12.5").unwrap());
+        assert_eq!(engine.accounts[&1].total, Decimal::from_str("12.5").unwrap());
+
+        let mut engine = TransactionEngine::builder().seed_duplicate_policy(SeedDuplicatePolicy::LastWins).build();
+        seed(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("2.5").unwrap());
+        assert_eq!(engine.accounts[&1].total, Decimal::from_str("2.5").unwrap());
+        assert_eq!(engine.accounts[&2].available, Decimal::from_str("5.0").unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let data = std::fs::read_to_string("tests/data/comprehensive.csv").unwrap();
+        let lines: Vec<_> = data.lines().collect();
+        let (header, rows) = lines.split_first().unwrap();
+        // Split right after the dispute of tx 10, so that the snapshot holds an open dispute
+        let (first_half, second_half) = rows.split_at(17);
+
+        let mut uninterrupted = TransactionEngine::new();
+        process(&mut uninterrupted, &data).unwrap();
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, &[&[*header], first_half].concat().join("\n")).unwrap();
+        let mut snapshot = Vec::new();
+        engine.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = TransactionEngine::new();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(balances(&restored), balances(&engine));
+        assert_eq!(restored.accounts[&3].disputed_transactions, engine.accounts[&3].disputed_transactions);
+
+        process(&mut restored, &[&[*header], second_half].concat().join("\n")).unwrap();
+        assert_eq!(balances(&restored), balances(&uninterrupted));
+    }
+
+    #[test]
+    fn test_control_file_pauses_processing() {
+        let control_file = std::env::temp_dir().join(format!("transactions_engine_{}_pause", std::process::id()));
+        std::fs::write(&control_file, "").unwrap();
+
+        let mut engine = TransactionEngine::builder().control_file(&control_file, Duration::from_millis(5)).build();
+        let handle = thread::spawn(move || {
+            process(&mut engine, "type,client,tx,amount
+                deposit,1,1,10.0").unwrap();
+            engine
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!handle.is_finished());
+
+        std::fs::remove_file(&control_file).unwrap();
+        let engine = handle.join().unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("10.0").unwrap());
+    }
+
+    #[test]
+    fn test_max_transaction_amount() {
+        let mut engine = TransactionEngine::builder()
+            .max_transaction_amount(Decimal::from_str("1000000").unwrap())
+            .build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,999999.9999
+            withdrawal,1,2,1000000").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("999999.9999").unwrap());
+
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,1,3,1000000.0001");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { .. })));
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("999999.9999").unwrap());
+    }
+
+    #[test]
+    fn test_min_deposit_amount() {
+        let mut engine = TransactionEngine::builder()
+            .min_deposit_amount(Decimal::from_str("0.01").unwrap())
+            .build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,0.01
+            deposit,1,2,0.02
+            withdrawal,1,3,0.005").unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("0.025").unwrap());
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,4,0.0099"), "Invalid transaction: Deposit amount 0.0099 is below the minimum of 0.01");
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("0.025").unwrap());
+    }
+
+    fn output(engine: &mut TransactionEngine) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        engine.output_account_balances_to_writer(&mut writer).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_internal_precision() {
+        let data = "type,client,tx,amount
+            deposit,1,1,0.00004
+            deposit,1,2,0.00004
+            deposit,1,3,0.00004";
+
+        // Rounding each deposit to 4 decimal places would apply zero deposits
+        let mut engine = TransactionEngine::builder().internal_precision(4).build();
+        assert_eq!(process_error(&mut engine, data),
+            "Invalid transaction: Transaction amount 0.00004 rounds to zero at the internal precision");
+        assert!(engine.accounts.is_empty());
+
+        // A higher internal precision only rounds the final balance
+        let mut engine = TransactionEngine::builder().internal_precision(8).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("0.00012").unwrap());
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n1,0.0001,0,0.0001,false\n");
+    }
+
+    #[test]
+    fn test_output_precision() {
+        let mut engine = TransactionEngine::builder().output_precision(2).build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.23456").unwrap();
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n1,1.23,0,1.23,false\n");
+    }
+
+    #[test]
+    fn test_chronological_order() {
+        let data = "type,client,tx,amount,timestamp
+            dispute,1,1,,300
+            withdrawal,1,2,4.0,200
+            deposit,1,1,10.0,100";
+
+        // In input order, the dispute and withdrawal are rejected before the deposit is made
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, data).unwrap();
+        engine.finish().unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("10.0").unwrap(), Decimal::ZERO, Decimal::from_str("10.0").unwrap(), false)]);
+
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+        process(&mut engine, data).unwrap();
+        assert!(engine.accounts.is_empty());
+        engine.finish().unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::ZERO, Decimal::from_str("6.0").unwrap(), Decimal::from_str("6.0").unwrap(), false)]);
+    }
+
+    #[test]
+    fn test_chronological_order_sequence() {
+        // Numbers the transactions backwards, the last one read comes first
+        struct Reversed(u64);
+        impl Sequence for Reversed {
+            fn next(&mut self) -> u64 {
+                self.0 -= 1;
+                self.0
+            }
+        }
+
+        let data = "type,client,tx,amount,timestamp
+            deposit,1,1,10.0,100
+            withdrawal,1,2,10.0,100
+            deposit,1,3,5.0,100";
+
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+        process(&mut engine, data).unwrap();
+        engine.finish().unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(5), Decimal::ZERO, Decimal::from(5), false)]);
+
+        // The withdrawal comes before the first deposit now, when only 5 are available
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+        engine.order_ties_with(Reversed(u64::MAX));
+        process(&mut engine, data).unwrap();
+        engine.finish().unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(15), Decimal::ZERO, Decimal::from(15), false)]);
+        assert_eq!(engine.stats().rejected, 1);
+    }
+
+    #[test]
+    fn test_chronological_order_requires_timestamps() {
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { .. })));
+    }
+
+    fn process_error(engine: &mut TransactionEngine, data: &str) -> String {
+        process(engine, data).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,10.0
+            withdrawal,1,3,1.0").unwrap();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            resolve,1,1,"), "Account error: Transaction not disputed");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            resolve,1,2,"), "Invalid transaction: Cannot resolve transaction from different client");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            resolve,1,3,"),
+            "Invalid transaction: Cannot resolve transaction 3: it is a withdrawal, only deposit transactions can be disputed");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            chargeback,1,4,"), "Invalid transaction: Cannot chargeback non-existent transaction: 4");
+    }
+
+    #[test]
+    fn test_locked_account_errors() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,10.0
+            dispute,1,1,
+            chargeback,1,1,").unwrap();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            dispute,1,2,"), "Account error: cannot dispute tx 2: account 1 is locked");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            withdrawal,1,3,1.0"), "Account error: cannot withdraw tx 3: account 1 is locked");
+    }
+
+    #[test]
+    fn test_strict_disputes() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,10.0
+            dispute,1,1,";
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].disputed_transactions[&1], Decimal::ZERO);
+
+        let mut engine = TransactionEngine::builder().strict_disputes(true).build();
+        process(&mut engine, data).unwrap();
+        assert!(engine.accounts[&1].disputed_transactions.is_empty());
+        assert_eq!(balances(&engine), vec![(1, Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, false)]);
+    }
+
+    #[test]
+    fn test_process_to_accounts() {
+        let data: &[u8] = b"type,client,tx,amount
+deposit,2,1,10.0
+deposit,1,2,5.0
+withdrawal,2,3,2.5
+dispute,1,2,";
+        let mut reader = csv::Reader::from_reader(data);
+
+        let accounts = process_to_accounts(&mut reader).unwrap();
+        assert_eq!(accounts.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(accounts[&1].held, Decimal::from_str("5.0").unwrap());
+        assert_eq!(accounts[&1].available, Decimal::ZERO);
+        assert_eq!(accounts[&2].available, Decimal::from_str("7.5").unwrap());
+    }
+
+    #[test]
+    fn test_zero_amount_dispute_is_skipped() {
+        // Validation never records a zero deposit, so the state is built directly
+        let mut engine = TransactionEngine::new();
+        engine.accounts.insert(1, Account::new(1));
+        engine.transaction_history.insert(1, Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::ZERO),
+            timestamp: None,
+            currency: None,
+        });
+
+        process(&mut engine, "type,client,tx,amount
+            dispute,1,1,").unwrap();
+        assert!(engine.accounts[&1].disputed_transactions.is_empty());
+        assert_eq!(balances(&engine), vec![(1, Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, false)]);
+    }
+
+    #[test]
+    fn test_currency() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount,currency
+            deposit,1,1,10.0,USD
+            withdrawal,1,2,2.0,USD
+            deposit,1,3,1.0,
+            dispute,1,3,,USD").unwrap();
+        assert_eq!(engine.accounts[&1].currency.as_deref(), Some("USD"));
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("8.0").unwrap(), Decimal::from_str("1.0").unwrap(),
+            Decimal::from_str("9.0").unwrap(), false)]);
+    }
+
+    #[test]
+    fn test_currency_mismatch() {
+        let data = "type,client,tx,amount,currency
+            deposit,1,1,10.0,USD
+            deposit,2,2,5.0,EUR
+            withdrawal,1,3,2.0,EUR";
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, data).unwrap();
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from_str("10.0").unwrap(), Decimal::ZERO, Decimal::from_str("10.0").unwrap(), false),
+            (2, Decimal::from_str("5.0").unwrap(), Decimal::ZERO, Decimal::from_str("5.0").unwrap(), false),
+        ]);
+
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, data), "Invalid transaction: currency mismatch");
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .overdraft_limit(Decimal::from_str("5.0").unwrap())
+            .output_precision(2)
+            .build();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.005
+            withdrawal,1,2,6.0").unwrap();
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n1,-5.00,0,-5.00,false\n");
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            withdrawal,1,3,0.01"), "Account error: Insufficient funds");
+    }
+
+    #[test]
+    fn test_unparseable_amount() {
+        let mut engine = TransactionEngine::new();
+        let error = process_error(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,1,2,abc");
+
+        assert!(error.contains("invalid amount 'abc'"), "{}", error);
+        assert!(error.contains("line: 3"), "{}", error);
+    }
+
+    #[test]
+    fn test_scientific_notation_amounts() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.5e3
+            withdrawal,1,2,2.5E-1").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("1499.75").unwrap(), Decimal::ZERO,
+            Decimal::from_str("1499.75").unwrap(), false)]);
+
+        // The mantissa is parsed as a decimal, not through f64, so every digit is kept exactly
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.00000000000000000001e2").unwrap();
+        assert_eq!(balances(&engine)[0].1, Decimal::from_str("100.000000000000000001").unwrap());
+
+        // Which means the usual precision checks apply to the expanded amount
+        let mut engine = TransactionEngine::builder().precision_validation(true).build();
+        assert!(process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.23456e-2").is_err());
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            freeze,1,2,").unwrap();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,3,5.0"), "Account error: cannot deposit tx 3: account 1 is frozen");
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("10.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("10.0").unwrap(), false)]);
+
+        process(&mut engine, "type,client,tx,amount
+            unfreeze,1,4,
+            deposit,1,5,5.0").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("15.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("15.0").unwrap(), false)]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            withdrawal,1,2,10.0
+            dispute,1,3,
+            withdrawal,1,4,1.0").unwrap();
+        assert_eq!(engine.stats(), ProcessingStats { processed: 4, applied: 2, rejected: 2, accounts: 1 });
+        assert_eq!(engine.stats().to_string(), "processed 4 transactions, 2 applied, 2 rejected, 1 accounts");
+    }
+
+    #[test]
+    fn test_dispute_charged_back_transaction() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,
+            chargeback,1,1,").unwrap();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            dispute,1,1,"), "Account error: Transaction already finalized");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            resolve,1,1,"), "Account error: Transaction already finalized");
+    }
+
+    #[test]
+    fn test_deferred_disputes() {
+        let mut engine = TransactionEngine::builder().strict(true).deferred_disputes(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            dispute,1,2,
+            deposit,1,3,1.0
+            deposit,1,2,10.0
+            dispute,1,4,").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("6.0").unwrap(), Decimal::from_str("10.0").unwrap(),
+            Decimal::from_str("16.0").unwrap(), false)]);
+
+        // The dispute of tx 4 never found its deposit
+        assert_eq!(engine.finish().unwrap_err().to_string(), "Invalid transaction: Cannot dispute non-existent transaction: 4");
+
+        // Disputes of withdrawals are deferred the same way
+        let mut engine = TransactionEngine::builder().strict(true).deferred_disputes(true).withdrawal_disputes(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,2,
+            withdrawal,1,2,4.0").unwrap();
+        engine.finish().unwrap();
+        assert!(engine.accounts[&1].disputed_withdrawals.contains(&2));
+    }
+
+    #[test]
+    fn test_snapshot_deferred_disputes() {
+        let mut engine = TransactionEngine::builder().strict(true).deferred_disputes(true).build();
+        process(&mut engine, "type,client,tx,amount
+            dispute,1,1,").unwrap();
+        let mut snapshot = Vec::new();
+        engine.save_snapshot(&mut snapshot).unwrap();
+
+        // The dispute still waits for its deposit once restored
+        let mut restored = TransactionEngine::builder().strict(true).deferred_disputes(true).build();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        process(&mut restored, "type,client,tx,amount
+            deposit,1,1,10.0").unwrap();
+        restored.finish().unwrap();
+        assert_eq!(balances(&restored), vec![(1, Decimal::ZERO, Decimal::from(10), Decimal::from(10), false)]);
+    }
+
+    #[test]
+    fn test_id_out_of_range() {
+        let mut engine = TransactionEngine::new();
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,65536,1,1.0"), "Invalid transaction: client id 65536 out of range (max 65535)");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,99999999999999999999999,1.0"),
+            "Invalid transaction: tx id 99999999999999999999999 out of range (max 4294967295)");
+
+        // Other malformed ids are still reported as CSV errors
+        assert!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,invalid,1.0").starts_with("CSV error"));
+    }
+
+    #[test]
+    fn test_transaction_hook() {
+        let applied = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = TransactionEngine::new();
+        let hook_applied = applied.clone();
+        engine.on_transaction_applied(move |transaction, account| {
+            hook_applied.lock().unwrap().push((transaction.tx, account.available));
+        });
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,20.0
+            withdrawal,1,3,4.0
+            dispute,1,1,").unwrap();
+
+        // The rejected withdrawal doesn't fire the hook
+        assert_eq!(*applied.lock().unwrap(), vec![
+            (1, Decimal::from_str("10.0").unwrap()),
+            (3, Decimal::from_str("6.0").unwrap()),
+            (1, Decimal::ZERO),
+        ]);
+    }
+
+    #[test]
+    fn test_rejection_hook() {
+        let rejected = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = TransactionEngine::new();
+        let hook_rejected = rejected.clone();
+        engine.on_transaction_rejected(move |transaction, err| {
+            hook_rejected.lock().unwrap().push((transaction.tx, err.reason()));
+        });
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,20.0
+            resolve,1,1,
+            dispute,1,9,
+            withdrawal,1,3,4.0").unwrap();
+
+        assert_eq!(*rejected.lock().unwrap(), vec![
+            (2, "Insufficient funds".to_string()),
+            (1, "Transaction not disputed".to_string()),
+            (9, "Cannot dispute non-existent transaction: 9".to_string()),
+        ]);
+        assert_eq!(engine.stats().rejected, 3);
+    }
+
+    #[test]
+    fn test_account_factory() {
+        let mut engine = TransactionEngine::new();
+        engine.create_accounts_with(|client| {
+            let mut account = Account::new(client);
+            account.available = Decimal::from(100);
+            account.total = Decimal::from(100);
+            account
+        });
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,2,2,30.0
+            deposit,1,3,5.0").unwrap();
+
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from_str("115.0").unwrap(), Decimal::ZERO, Decimal::from_str("115.0").unwrap(), false),
+            (2, Decimal::from_str("70.0").unwrap(), Decimal::ZERO, Decimal::from_str("70.0").unwrap(), false),
+        ]);
+    }
+
+    #[test]
+    fn test_history_window() {
+        let mut engine = TransactionEngine::builder().strict(true).history_window(2).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,10.0
+            dispute,1,2,
+            deposit,1,3,10.0
+            deposit,1,4,10.0").unwrap();
+
+        // The disputed tx 2 is kept until its dispute is settled
+        let mut history: Vec<_> = engine.transaction_history.keys().copied().collect();
+        history.sort();
+        assert_eq!(history, vec![2, 3, 4]);
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            dispute,1,1,"), "Invalid transaction: Cannot dispute transaction 1: it is out of the history window");
+        process(&mut engine, "type,client,tx,amount
+            resolve,1,2,
+            dispute,1,4,").unwrap();
+        assert_eq!(engine.accounts[&1].held, Decimal::from_str("10.0").unwrap());
+
+        // Replaying the pruned deposit is a no-op
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0").unwrap();
+        assert_eq!(engine.accounts[&1].total, Decimal::from_str("40.0").unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_history_window() {
+        let mut engine = TransactionEngine::builder().strict(true).history_window(2).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,9,10.0
+            deposit,1,5,10.0
+            deposit,1,1,10.0").unwrap();
+        let mut snapshot = Vec::new();
+        engine.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = TransactionEngine::builder().strict(true).history_window(2).build();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+
+        // The window still evicts in insertion order, tx 5 rather than tx 1, and remembers the pruned transactions
+        process(&mut restored, "type,client,tx,amount
+            deposit,1,2,10.0").unwrap();
+        let mut history: Vec<_> = restored.transaction_history.keys().copied().collect();
+        history.sort();
+        assert_eq!(history, vec![1, 2]);
+        for tx in [9, 5] {
+            assert_eq!(process_error(&mut restored, &format!("type,client,tx,amount\ndispute,1,{},", tx)),
+                format!("Invalid transaction: Cannot dispute transaction {}: it is out of the history window", tx));
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_fees() {
+        let data = "type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,50.0";
+
+        let mut engine = TransactionEngine::builder().withdrawal_fee(Decimal::from_str("1.5").unwrap()).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("48.5").unwrap());
+        assert_eq!(engine.collected_fees(), Decimal::from_str("1.5").unwrap());
+
+        let mut engine = TransactionEngine::builder().withdrawal_fee_percentage(Decimal::from_str("2").unwrap()).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("49").unwrap());
+        assert_eq!(engine.collected_fees(), Decimal::from_str("1").unwrap());
+
+        // A percentage fee is rounded to the internal precision
+        let mut engine = TransactionEngine::builder()
+            .internal_precision(2)
+            .withdrawal_fee_percentage(Decimal::from_str("1.5").unwrap())
+            .build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,0.99").unwrap();
+        assert_eq!(engine.collected_fees(), Decimal::from_str("0.01").unwrap());
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("99.00").unwrap());
+    }
+
+    #[test]
+    fn test_withdrawal_fee_insufficient_funds() {
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .withdrawal_fee(Decimal::from_str("0.01").unwrap())
+            .build();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,10.0"), "Account error: Insufficient funds");
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("10.0").unwrap());
+        assert_eq!(engine.collected_fees(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_signed_amounts() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,-5.0
+            withdrawal,1,3,-1.0";
+
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, data), "Invalid transaction: Transaction amount must be positive");
+
+        let mut engine = TransactionEngine::builder().strict(true).signed_amounts(true).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.transaction_history[&2].transaction_type, TransactionType::Withdrawal);
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("6.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("6.0").unwrap(), false)]);
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,4.0").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("6.0").unwrap(), Decimal::from_str("4.0").unwrap(),
+            Decimal::from_str("10.0").unwrap(), false)]);
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            dispute,1,1,6.5"), "Invalid transaction: Cannot dispute 6.5 of transaction 1: only 6 of its amount 10 is not disputed yet");
+
+        // The portions add up to the full amount, which is charged back at once
+        process(&mut engine, "type,client,tx,amount
+            dispute,1,1,6.0
+            chargeback,1,1,").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, true)]);
+
+        // A portion counts in full even when less than it could be held
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,2,2,10.0
+            withdrawal,2,3,8.0
+            dispute,2,2,5.0
+            dispute,2,2,6.0"), "Invalid transaction: Cannot dispute 6 of transaction 2: only 5 of its amount 10 is not disputed yet");
+        assert_eq!(engine.accounts[&2].held, Decimal::from(2));
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.5
+            deposit,3,3,1.0
+            dispute,1,1,
+            dispute,2,2,
+            dispute,3,3,
+            chargeback,3,3,").unwrap();
+
+        assert_eq!(engine.total_held(), Decimal::from_str("15.5").unwrap());
+        assert_eq!(engine.total_available(), Decimal::ZERO);
+        assert_eq!(engine.total_locked_count(), 1);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "").unwrap();
+        process(&mut engine, "type,client,tx,amount").unwrap();
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n");
+
+        // The empty input doesn't set the expected headers
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.0").unwrap();
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n1,1,0,1,false\n");
+    }
+
+    #[test]
+    fn test_require_existing_accounts() {
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .require_existing_accounts(true)
+            .provisioned_clients([1])
+            .build();
+        seed(&mut engine, "client,balance
+            2,5.0").unwrap();
+
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,2,2,1.0").unwrap();
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,3,3,10.0"), "Invalid transaction: Client 3 has no provisioned account");
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            withdrawal,4,4,10.0"), "Invalid transaction: Client 4 has no provisioned account");
+
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from_str("10.0").unwrap(), Decimal::ZERO, Decimal::from_str("10.0").unwrap(), false),
+            (2, Decimal::from_str("4.0").unwrap(), Decimal::ZERO, Decimal::from_str("4.0").unwrap(), false),
+        ]);
+    }
+
+    #[test]
+    fn test_thousands_separators() {
+        // Commas only survive as separators in a quoted field, or with another delimiter
+        let data = "type;client;tx;amount
+            deposit;1;1;1_000.50
+            deposit;1;2;\"1,000.50\"
+            deposit;1;3;1 000";
+        let reader = || csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+
+        let mut engine = TransactionEngine::builder().thousands_separators(true).build();
+        engine.process_transactions_from_reader(&mut reader()).unwrap();
+        assert_eq!(engine.accounts[&1].available, Decimal::from_str("3001.00").unwrap());
+
+        let mut engine = TransactionEngine::new();
+        let error = engine.process_transactions_from_reader(&mut reader()).unwrap_err().to_string();
+        assert!(error.contains("invalid amount '1_000.50'"), "{}", error);
+
+        // Separators anywhere else are not stripped, and the amount is rejected
+        let mut engine = TransactionEngine::builder().thousands_separators(true).build();
+        let error = process_error(&mut engine, "type,client,tx,amount
+            deposit,1,1,\"1,5\"");
+        assert!(error.contains("invalid amount '1,5'"), "{}", error);
+        let error = process_error(&mut engine, "type,client,tx,amount
+            deposit,1,2,1_0.5");
+        assert!(error.contains("invalid amount '1_0.5'"), "{}", error);
+    }
+
+    #[test]
+    fn test_lenient_resolve() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            resolve,1,1,";
+
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, data), "Account error: Transaction not disputed");
+
+        let mut engine = TransactionEngine::builder().strict(true).lenient_resolve(true).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("10.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("10.0").unwrap(), false)]);
+
+        // Resolving an unknown transaction is still an error
+        assert!(process_error(&mut engine, "type,client,tx,amount
+            resolve,1,2,").contains("Cannot resolve non-existent transaction: 2"));
+    }
+
+    #[test]
+    fn test_invalid_transaction_context() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,7,3,-1.0");
+        let Err(EngineError::InvalidTransaction { message, tx, client }) = result else {
+            panic!("unexpected result {:?}", result);
+        };
+        assert_eq!((message.as_str(), tx, client), ("Transaction amount must be positive", Some(3), Some(7)));
+
+        let result = process(&mut engine, "type,client,tx,amount
+            dispute,2,9,");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { tx: Some(9), client: Some(2), .. })));
+
+        // Rows that could not be parsed have no transaction to report
+        let result = process(&mut engine, "type,client,tx,amount
+            deposit,70000,1,1.0");
+        assert!(matches!(result, Err(EngineError::InvalidTransaction { tx: None, client: None, .. })));
+    }
+
+    #[test]
+    fn test_output_order() {
+        let mut engine = TransactionEngine::new();
+        // Clients inserted in a scrambled order, 7919 being coprime with 1000 every id appears once
+        let data: String = (0..1000u32)
+            .map(|tx| format!("deposit,{},{},1.0\n", tx * 7919 % 1000, tx + 1))
+            .collect();
+        process(&mut engine, &format!("type,client,tx,amount\n{}", data)).unwrap();
+
+        let clients: Vec<u16> = engine.account_records().iter().map(|record| record.client).collect();
+        assert_eq!(clients, (0..1000).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn test_monotonic_tx_ids() {
+        let mut engine = TransactionEngine::builder().monotonic_tx_ids(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,5,10.0
+            withdrawal,1,9,20.0
+            dispute,1,1,
+            deposit,1,10,1.0").unwrap();
+
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,2,7,1.0"), "Invalid transaction: Transaction ID 7 is not above the previous transaction ID 10");
+
+        // The last tx id is kept across a snapshot, including the one of a rejected withdrawal
+        let mut snapshot = Vec::new();
+        engine.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = TransactionEngine::builder().monotonic_tx_ids(true).build();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(process_error(&mut restored, "type,client,tx,amount
+            deposit,2,8,1.0"), "Invalid transaction: Transaction ID 8 is not above the previous transaction ID 10");
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,10,1.0
+            deposit,1,7,1.0").unwrap();
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,").unwrap();
+
+        engine.reset();
+        assert!(engine.account_records().is_empty());
+        assert_eq!(engine.stats().processed, 0);
+
+        // Tx 1 is not a duplicate anymore, and the strict mode is kept
+        process(&mut engine, "type,client,tx,amount
+            deposit,2,1,5.0").unwrap();
+        assert_eq!(balances(&engine), vec![(2, Decimal::from_str("5.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("5.0").unwrap(), false)]);
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            withdrawal,2,2,6.0"), "Account error: Insufficient funds");
+    }
+
+    #[test]
+    fn test_max_accounts() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,10.0
+            withdrawal,1,3,5.0
+            deposit,3,4,10.0";
+
+        let mut engine = TransactionEngine::builder().max_accounts(3).strict(true).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(engine.account_records().len(), 3);
+
+        let mut engine = TransactionEngine::builder().max_accounts(2).strict(true).build();
+        assert_eq!(process_error(&mut engine, data), "Invalid transaction: account limit exceeded");
+
+        // Lenient mode skips the transactions of the clients beyond the limit
+        let mut engine = TransactionEngine::builder().max_accounts(2).build();
+        process(&mut engine, data).unwrap();
+        process(&mut engine, "type,client,tx,amount
+            deposit,2,5,1.0").unwrap();
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(balances(&engine).iter().map(|balance| balance.0).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_max_accounts_seed() {
+        let mut engine = TransactionEngine::builder().max_accounts(2).build();
+        assert_eq!(seed(&mut engine, "client,balance
+            1,10.0
+            2,5.0
+            3,1.0").unwrap_err().to_string(), "Invalid seed balance: Seed balance for client 3 exceeds the account limit");
+
+        // Seeded accounts count towards the limit of the transactions
+        process(&mut engine, "type,client,tx,amount
+            deposit,2,1,1.0
+            deposit,4,2,1.0").unwrap();
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(balances(&engine).iter().map(|balance| balance.0).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_account_json() {
+        let mut engine = TransactionEngine::builder().output_precision(2).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.125
+            deposit,1,2,2.0
+            dispute,1,2,").unwrap();
+
+        assert_eq!(engine.account_json(1).unwrap(),
+            r#"{"client":1,"available":"10.12","held":"2","total":"12.12","locked":false}"#);
+        assert_eq!(engine.account_json(2), None);
+    }
+
+    #[test]
+    fn test_dispute_after_resolve() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,5.0
+            dispute,1,1,
+            resolve,1,1,
+            dispute,1,1,").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("5.0").unwrap(), Decimal::from_str("10.0").unwrap(),
+            Decimal::from_str("15.0").unwrap(), false)]);
+
+        process(&mut engine, "type,client,tx,amount
+            chargeback,1,1,").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("5.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("5.0").unwrap(), true)]);
+    }
+
+    #[test]
+    fn test_withdrawal_disputes() {
+        let data = "type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,30.0
+            dispute,1,2,";
+
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, data),
+            "Invalid transaction: Cannot dispute transaction 2: it is a withdrawal, only deposit transactions can be disputed");
+
+        let mut engine = TransactionEngine::builder().strict(true).withdrawal_disputes(true).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("70.0").unwrap(), Decimal::from_str("30.0").unwrap(),
+            Decimal::from_str("100.0").unwrap(), false)]);
+
+        // The withdrawn funds are credited back
+        process(&mut engine, "type,client,tx,amount
+            chargeback,1,2,").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("100.0").unwrap(), Decimal::ZERO,
+            Decimal::from_str("100.0").unwrap(), true)]);
+    }
+
+    #[test]
+    fn test_disputable_types() {
+        let data = "type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,30.0
+            dispute,1,2,";
+
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .disputable_types([TransactionType::Deposit, TransactionType::Withdrawal])
+            .build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from_str("70.0").unwrap(), Decimal::from_str("30.0").unwrap(),
+            Decimal::from_str("100.0").unwrap(), false)]);
+
+        // Deposits are no longer disputable once left out of the set
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .disputable_types([TransactionType::Withdrawal])
+            .build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            dispute,1,1,"),
+            "Invalid transaction: Cannot dispute transaction 1: it is a deposit, only withdrawal transactions can be disputed");
+
+        // Disabling withdrawal disputes only removes withdrawals from the set
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .disputable_types([TransactionType::Deposit, TransactionType::Withdrawal])
+            .withdrawal_disputes(false)
+            .build();
+        assert_eq!(process_error(&mut engine, data),
+            "Invalid transaction: Cannot dispute transaction 2: it is a withdrawal, only deposit transactions can be disputed");
+    }
+
+    #[test]
+    fn test_flagged_clients() {
+        let mut engine = TransactionEngine::builder()
+            .flagged_disputes_threshold(1)
+            .flagged_held_threshold(Decimal::from(50))
+            .build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,2,10.0
+            deposit,2,3,100.0
+            deposit,3,4,10.0
+            dispute,1,1,
+            dispute,3,4,").unwrap();
+        assert_eq!(engine.flagged_clients(), Vec::<u16>::new());
+
+        process(&mut engine, "type,client,tx,amount
+            dispute,1,2,
+            dispute,2,3,").unwrap();
+        assert_eq!(engine.flagged_clients(), vec![1, 2]);
+
+        // Flagged clients are only reported, and no longer flagged once their disputes are resolved
+        process(&mut engine, "type,client,tx,amount
+            resolve,1,1,
+            deposit,1,5,1.0").unwrap();
+        assert_eq!(engine.flagged_clients(), vec![2]);
+        assert_eq!(engine.stats().rejected, 0);
+    }
+
+    #[test]
+    fn test_stop_after_tx() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.0
+            withdrawal,1,3,4.0
+            deposit,1,4,100.0";
+
+        let mut engine = TransactionEngine::builder().stop_after_tx(3).build();
+        process(&mut engine, data).unwrap();
+        process(&mut engine, "type,client,tx,amount
+            deposit,3,5,1.0").unwrap();
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from_str("6.0").unwrap(), Decimal::ZERO, Decimal::from_str("6.0").unwrap(), false),
+            (2, Decimal::from_str("5.0").unwrap(), Decimal::ZERO, Decimal::from_str("5.0").unwrap(), false),
+        ]);
+        assert_eq!(engine.stats().processed, 3);
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,2,1,10.0
+            deposit,1,2,5.0
+            deposit,2,3,2.5
+            deposit,1,4,1.0
+            deposit,1,5,3.0
+            dispute,2,3,
+            dispute,1,5,
+            dispute,2,1,
+            dispute,1,2,
+            dispute,1,4,
+            resolve,1,4,").unwrap();
+
+        assert_eq!(engine.open_disputes(), vec![
+            (1, 2, Decimal::from_str("5.0").unwrap()),
+            (1, 5, Decimal::from_str("3.0").unwrap()),
+            (2, 1, Decimal::from_str("10.0").unwrap()),
+            (2, 3, Decimal::from_str("2.5").unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn test_stop_flag() {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let mut engine = TransactionEngine::builder().stop_flag(stop_flag.clone()).build();
+
+        // Interrupted as if by a signal while the second transaction is applied
+        engine.on_transaction_applied(move |transaction, _| {
+            if transaction.tx == 2 {
+                stop_flag.store(true, Ordering::Relaxed);
+            }
+        });
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.0
+            withdrawal,1,3,4.0").unwrap();
+
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n1,10,0,10,false\n2,5,0,5,false\n");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.0
+            dispute,2,2,").unwrap();
+
+        let mut shard = TransactionEngine::new();
+        process(&mut shard, "type,client,tx,amount
+            deposit,3,3,7.0
+            withdrawal,3,4,2.0").unwrap();
+
+        engine.merge(shard).unwrap();
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from(10), Decimal::ZERO, Decimal::from(10), false),
+            (2, Decimal::ZERO, Decimal::from(5), Decimal::from(5), false),
+            (3, Decimal::from(5), Decimal::ZERO, Decimal::from(5), false),
+        ]);
+        assert_eq!(engine.stats().processed, 5);
+
+        // The merged history can still be disputed
+        process(&mut engine, "type,client,tx,amount
+            dispute,3,3,
+            resolve,2,2,").unwrap();
+        assert_eq!(balances(&engine)[2], (3, Decimal::ZERO, Decimal::from(5), Decimal::from(5), false));
+    }
+
+    #[test]
+    fn test_merge_unfinished() {
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+        process(&mut engine, "type,client,tx,amount,timestamp
+            deposit,1,1,10.0,2").unwrap();
+
+        // The transactions buffered by the shards are applied or rejected when this engine finishes
+        let mut chronological = TransactionEngine::builder().chronological_order(true).build();
+        process(&mut chronological, "type,client,tx,amount,timestamp
+            deposit,2,2,5.0,1").unwrap();
+        let mut deferred = TransactionEngine::builder().deferred_disputes(true).build();
+        process(&mut deferred, "type,client,tx,amount
+            deposit,3,3,7.0
+            dispute,3,9,").unwrap();
+        engine.merge(chronological).unwrap();
+        engine.merge(deferred).unwrap();
+        assert_eq!(engine.accounts.keys().collect::<Vec<_>>(), vec![&3]);
+
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let rejected_hook = Arc::clone(&rejected);
+        engine.on_transaction_rejected(move |transaction, _| rejected_hook.lock().unwrap().push(transaction.tx));
+        engine.finish().unwrap();
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from(10), Decimal::ZERO, Decimal::from(10), false),
+            (2, Decimal::from(5), Decimal::ZERO, Decimal::from(5), false),
+            (3, Decimal::from(7), Decimal::ZERO, Decimal::from(7), false),
+        ]);
+        assert_eq!(*rejected.lock().unwrap(), vec![9]);
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0").unwrap();
+
+        let mut shard = TransactionEngine::new();
+        process(&mut shard, "type,client,tx,amount
+            deposit,1,2,5.0").unwrap();
+        assert_eq!(engine.merge(shard).unwrap_err().to_string(),
+            "Overlapping clients: client 1 has an account in both engines");
+
+        let mut shard = TransactionEngine::new();
+        process(&mut shard, "type,client,tx,amount
+            deposit,2,1,5.0").unwrap();
+        assert_eq!(engine.merge(shard).unwrap_err().to_string(),
+            "Invalid transaction: Cannot merge transaction 1: it was processed by both engines");
+
+        // A transaction pruned from the history of either engine still counts as processed
+        let mut shard = TransactionEngine::builder().history_window(1).build();
+        process(&mut shard, "type,client,tx,amount
+            deposit,2,1,5.0
+            deposit,2,2,5.0").unwrap();
+        assert_eq!(engine.merge(shard).unwrap_err().to_string(),
+            "Invalid transaction: Cannot merge transaction 1: it was processed by both engines");
+
+        let mut pruned = TransactionEngine::builder().history_window(1).build();
+        process(&mut pruned, "type,client,tx,amount
+            deposit,3,3,5.0
+            deposit,3,4,5.0").unwrap();
+        let mut shard = TransactionEngine::new();
+        process(&mut shard, "type,client,tx,amount
+            deposit,2,3,5.0").unwrap();
+        assert_eq!(pruned.merge(shard).unwrap_err().to_string(),
+            "Invalid transaction: Cannot merge transaction 3: it was processed by both engines");
+
+        // Nothing was merged
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(10), Decimal::ZERO, Decimal::from(10), false)]);
+    }
+
+    #[test]
+    fn test_output_sort_by_total() {
+        let mut engine = TransactionEngine::builder().output_sort(OutputSort::Total).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,5.0
+            deposit,2,2,10.0
+            deposit,3,3,5.0
+            deposit,4,4,1.0").unwrap();
+
+        assert_eq!(output(&mut engine),
+            "client,available,held,total,locked\n2,10,0,10,false\n1,5,0,5,false\n3,5,0,5,false\n4,1,0,1,false\n");
+    }
+
+    #[test]
+    fn test_enabled_types() {
+        let mut engine = TransactionEngine::builder()
+            .strict(true)
+            .enabled_types([TransactionType::Deposit, TransactionType::Withdrawal])
+            .build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.0
+            dispute,1,1,
+            chargeback,1,1,
+            withdrawal,2,3,2.0
+            resolve,2,2,").unwrap();
+
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from(10), Decimal::ZERO, Decimal::from(10), false),
+            (2, Decimal::from(3), Decimal::ZERO, Decimal::from(3), false),
+        ]);
+    }
+
+    #[test]
+    fn test_failed_withdrawals_lock() {
+        let mut engine = TransactionEngine::builder().failed_withdrawals_lock_threshold(3).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,20.0
+            withdrawal,1,3,20.0
+            withdrawal,1,4,5.0
+            withdrawal,1,5,20.0
+            withdrawal,1,6,20.0").unwrap();
+        // The successful withdrawal reset the count
+        assert!(!engine.accounts[&1].locked);
+
+        process(&mut engine, "type,client,tx,amount
+            withdrawal,1,7,20.0").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(5), Decimal::ZERO, Decimal::from(5), true)]);
+        assert_eq!(engine.lock_changelog(), [LockChange { client: 1, tx: 7, locked: true }]);
+
+        // Disabled by default
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,20.0
+            withdrawal,1,3,20.0
+            withdrawal,1,4,20.0").unwrap();
+        assert!(!engine.accounts[&1].locked);
+    }
+
+    #[test]
+    fn test_minor_units_output() {
+        let mut engine = TransactionEngine::builder().minor_units(4).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.2345
+            deposit,2,2,3").unwrap();
+        assert_eq!(output(&mut engine),
+            "client,available_minor,held_minor,total_minor,locked\n1,12345,0,12345,false\n2,30000,0,30000,false\n");
+
+        let mut engine = TransactionEngine::builder().minor_units(2).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.2345").unwrap();
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        assert_eq!(engine.output_account_balances_to_writer(&mut wtr).unwrap_err().to_string(),
+            "Account error: balance 1.2345 of client 1 is not a whole number of minor units at scale 2");
+
+        // Balances are converted before any rounding to the output precision, which would hide the last digit here
+        let mut engine = TransactionEngine::builder().minor_units(5).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.23456").unwrap();
+        assert_eq!(output(&mut engine),
+            "client,available_minor,held_minor,total_minor,locked\n1,123456,0,123456,false\n");
+        let mut engine = TransactionEngine::builder().minor_units(4).build();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,1.23456").unwrap();
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        assert!(engine.output_account_balances_to_writer(&mut wtr).is_err());
+    }
+
+    #[test]
+    fn test_totals_row() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.5
+            deposit,2,2,4.25
+            dispute,2,2,
+            deposit,1,3,1.0
+            dispute,1,3,
+            chargeback,1,3,").unwrap();
+        let records = engine.account_records();
+
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        write_totals_row(&records, None, &mut wtr).unwrap();
+        assert_eq!(String::from_utf8(wtr.into_inner().unwrap()).unwrap(), "total,10.5,4.25,14.75,1\n");
+
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        write_totals_row(&records, Some(2), &mut wtr).unwrap();
+        assert_eq!(String::from_utf8(wtr.into_inner().unwrap()).unwrap(), "total,1050,425,1475,1\n");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_reader() {
+        let data = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            deposit, 2, 2, 5.0
+            withdrawal, 1, 3, 20.0
+            dispute, 2, 2,
+            withdrawal, 1, 4, 2.5";
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, data).unwrap();
+
+        let mut async_engine = TransactionEngine::new();
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_reader(data.as_bytes());
+        async_engine.process_transactions_from_async_reader(&mut reader).await.unwrap();
+
+        assert_eq!(output(&mut async_engine), output(&mut engine));
+        assert_eq!(async_engine.stats().rejected, 1);
+
+        // Parse errors still point at the offending line
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_reader("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,abc".as_bytes());
+        let error = TransactionEngine::new().process_transactions_from_async_reader(&mut reader).await
+            .unwrap_err().to_string();
+        assert!(error.contains("invalid amount 'abc'") && error.contains("line: 3"), "{}", error);
+    }
+
+    #[test]
+    fn test_trim() {
+        let data = "type,client,tx,amount\ndeposit,1,1, 1.0\n";
+
+        let mut engine = TransactionEngine::new();
+        engine.process_transactions_from_reader(&mut engine.input_reader(data.as_bytes())).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::ONE, Decimal::ZERO, Decimal::ONE, false)]);
+
+        // The leading space is kept, the amount doesn't parse anymore
+        let mut engine = TransactionEngine::builder().trim(Trim::None).build();
+        let error = engine.process_transactions_from_reader(&mut engine.input_reader(data.as_bytes()))
+            .unwrap_err().to_string();
+        assert!(error.contains("invalid amount ' 1.0'"), "{}", error);
+
+        let mut engine = TransactionEngine::builder().trim(Trim::Headers).build();
+        let data = "type, client, tx, amount\ndeposit,1,1,1.0\n";
+        engine.process_transactions_from_reader(&mut engine.input_reader(data.as_bytes())).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::ONE, Decimal::ZERO, Decimal::ONE, false)]);
+    }
+
+    #[test]
+    fn test_allow_truncated() {
+        let data = "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,4.0
+            deposit,1,";
+
+        let mut engine = TransactionEngine::new();
+        assert!(process_error(&mut engine, data).contains("found record with 3 fields"));
+
+        let mut engine = TransactionEngine::builder().allow_truncated(true).build();
+        process(&mut engine, data).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(6), Decimal::ZERO, Decimal::from(6), false)]);
+
+        // Only the last row may be truncated
+        let mut engine = TransactionEngine::builder().allow_truncated(true).build();
+        assert!(process(&mut engine, "type,client,tx,amount
+            deposit,1,
+            deposit,1,1,10.0").is_err());
+    }
+
+    #[test]
+    fn test_currency_output_precision() {
+        let mut engine = TransactionEngine::builder()
+            .currency_output_precision("JPY", 0)
+            .currency_output_precision("BTC", 8)
+            .build();
+        process(&mut engine, "type,client,tx,amount,currency
+            deposit,1,1,1500.6,JPY
+            deposit,2,2,0.123456789,BTC
+            deposit,3,3,1.23456,").unwrap();
+
+        assert_eq!(output(&mut engine), "client,available,held,total,locked\n\
+            1,1501,0,1501,false\n2,0.12345679,0,0.12345679,false\n3,1.2346,0,1.2346,false\n");
+    }
+
+    #[test]
+    fn test_client_ids() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,3,1,10.0
+            deposit,1,2,5.0
+            withdrawal,1,3,5.0
+            deposit,2,4,1.0").unwrap();
+
+        // Client 1 has nothing left but is still part of the roster
+        assert_eq!(engine.client_ids(), vec![1, 2, 3]);
+        assert_eq!(balances(&engine)[0], (1, Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, false));
+    }
+
+    #[test]
+    fn test_dispute_of_dropped_account() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,2,2,5.0").unwrap();
+
+        // Drop the account of client 1 from the snapshot, its deposit stays in the history
+        let mut snapshot = Vec::new();
+        engine.save_snapshot(&mut snapshot).unwrap();
+        let mut snapshot: serde_json::Value = serde_json::from_slice(&snapshot).unwrap();
+        snapshot["accounts"].as_array_mut().unwrap().retain(|account| account["client"] != 1);
+
+        let mut restored = TransactionEngine::builder().strict(true).build();
+        restored.load_snapshot(snapshot.to_string().as_bytes()).unwrap();
+
+        let result = process(&mut restored, "type,client,tx,amount
+            dispute,1,1,");
+        assert!(matches!(result, Err(EngineError::AccountNotFound { client: 1, tx: 1 })));
+        assert_eq!(result.unwrap_err().to_string(), "Account error: Account of client 1 not found for transaction 1");
+    }
+
+    #[test]
+    fn test_process_bytes() {
+        let mut engine = TransactionEngine::new();
+        engine.process_bytes(b"type, client, tx, amount\ndeposit, 1, 1, 3.5\nwithdrawal, 1, 2, 1.25\n").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::new(225, 2), Decimal::ZERO, Decimal::new(225, 2), false)]);
+
+        // Transactions buffered in chronological order are applied before returning
+        let mut engine = TransactionEngine::builder().chronological_order(true).build();
+        engine.process_bytes(b"type,client,tx,amount,timestamp\nwithdrawal,1,2,1.25,20\ndeposit,1,1,3.5,10\n").unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::new(225, 2), Decimal::ZERO, Decimal::new(225, 2), false)]);
+    }
+
+    #[test]
+    fn test_process_random_bytes() {
+        // Xorshift generator, deterministic so that failures can be reproduced
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = b"deposit,withdrawal,dispute,resolve,chargeback,0123456789.-e \n\"";
+
+        for _ in 0..500 {
+            let len = (next() % 200) as usize;
+            let mut data = b"type,client,tx,amount\n".to_vec();
+            for _ in 0..len {
+                // Mostly CSV-like content, to get past the parser, and some arbitrary bytes
+                let byte = next();
+                data.push(if byte % 8 == 0 { (byte >> 8) as u8 } else { alphabet[(byte >> 8) as usize % alphabet.len()] });
+            }
+
+            // Errors are fine, panics are not
+            let builders = [
+                TransactionEngine::builder(),
+                TransactionEngine::builder().chronological_order(true),
+                TransactionEngine::builder().deferred_disputes(true).withdrawal_disputes(true),
+            ];
+            for builder in builders {
+                let mut engine = builder.build();
+                let _ = engine.process_bytes(&data);
+                let _ = engine.process_bytes(&data[22..]);
+                output(&mut engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_amount_is_an_error() {
+        // Validation rejects deposits and withdrawals without an amount, so the handlers are called directly
+        let mut engine = TransactionEngine::new();
+        let mut transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let error = engine.process_deposit(&transaction).unwrap_err();
+        assert!(matches!(error, EngineError::InvalidTransaction { tx: Some(1), client: Some(1), .. }));
+        assert_eq!(error.to_string(), "Invalid transaction: Transaction 1 has no amount");
+
+        transaction.transaction_type = TransactionType::Withdrawal;
+        assert!(matches!(engine.process_withdrawal(&transaction),
+            Err(EngineError::InvalidTransaction { tx: Some(1), .. })));
+
+        // A dispute of a recorded deposit without an amount is rejected as well
+        engine.accounts.insert(1, Account::new(1));
+        transaction.transaction_type = TransactionType::Deposit;
+        engine.transaction_history.insert(1, transaction);
+        let result = process(&mut engine, "type,client,tx,amount
+            dispute,1,1,");
+        assert!(result.is_ok());
+        assert_eq!(engine.stats().rejected, 1);
+        assert!(engine.accounts[&1].disputed_transactions.is_empty());
+    }
+
+    #[test]
+    fn test_disjoint_files_keep_state_and_callbacks() {
+        let files = ["tests/data/disjoint_part1.csv", "tests/data/disjoint_part2.csv"];
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = TransactionEngine::new();
+        seed(&mut engine, "client,balance
+            1,10.0
+            3,7.0").unwrap();
+        let hook_applied = Arc::clone(&applied);
+        engine.on_transaction_applied(move |transaction, _| hook_applied.lock().unwrap().push(transaction.tx));
+        engine.create_accounts_with(|client| {
+            let mut account = Account::new(client);
+            account.currency = Some("EUR".to_string());
+            account
+        });
+
+        // Seeded clients are not taken as being in several files, and their balances are kept
+        engine.process_disjoint_files(&files).unwrap();
+        assert_eq!(balances(&engine), vec![
+            (1, Decimal::from(16), Decimal::ZERO, Decimal::from(16), false),
+            (2, Decimal::ZERO, Decimal::from(20), Decimal::from(20), false),
+            (3, Decimal::new(85, 1), Decimal::ZERO, Decimal::new(85, 1), true),
+        ]);
+        assert_eq!(engine.accounts[&2].currency.as_deref(), Some("EUR"));
+        let mut txs = applied.lock().unwrap().clone();
+        txs.sort_unstable();
+        assert_eq!(txs, vec![1, 2, 2, 3, 4, 4, 4, 5]);
+
+        // The callbacks are given back to the engine afterwards
+        process(&mut engine, "type,client,tx,amount
+            deposit,4,6,1.0").unwrap();
+        assert_eq!(applied.lock().unwrap().last(), Some(&6));
+        assert_eq!(engine.accounts[&4].currency.as_deref(), Some("EUR"));
+    }
+
+    #[test]
+    fn test_disjoint_files_stop_flag() {
+        let stop_flag = Arc::new(AtomicBool::new(true));
+        let mut engine = TransactionEngine::builder().stop_flag(Arc::clone(&stop_flag)).build();
+        seed(&mut engine, "client,balance
+            1,10.0").unwrap();
+
+        engine.process_disjoint_files(&["tests/data/disjoint_part1.csv", "tests/data/disjoint_part2.csv"]).unwrap();
+        assert_eq!(balances(&engine), vec![(1, Decimal::from(10), Decimal::ZERO, Decimal::from(10), false)]);
+        assert_eq!(engine.stats().processed, 0);
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let mut engine = TransactionEngine::builder().strict(true).build();
+        assert_eq!(process_error(&mut engine, "type,client,tx,amount
+            deposit,1,1,79228162514264337593543950335
+            deposit,1,2,1"), "Account error: Balance out of the range of the amount type");
+        assert_eq!(engine.accounts[&1].available, Decimal::MAX);
+    }
+}
@@ -5,8 +5,26 @@ use crate::account::AccountError;
 pub enum EngineError {
     IoError(std::io::Error),
     CsvError(csv::Error),
-    InvalidTransaction(String),
+    #[cfg(feature = "async")]
+    AsyncCsvError(csv_async::Error),
+    JsonError(serde_json::Error),
+    // The tx id and client of the offending transaction, when the error is about a parsed transaction
+    InvalidTransaction {
+        message: String,
+        tx: Option<u32>,
+        client: Option<u16>,
+    },
     AccountError(String),
+    // A transaction referencing a client without an account, e.g. a dispute of a transaction still in the history
+    // after the account was dropped from a snapshot
+    AccountNotFound {
+        client: u16,
+        tx: u32,
+    },
+    SchemaDrift(String),
+    InvalidSeedBalance(String),
+    OverlappingClients(String),
+    InvalidConfig(String),
 }
 
 impl fmt::Display for EngineError {
@@ -14,8 +32,41 @@ impl fmt::Display for EngineError {
         match self {
             EngineError::IoError(err) => write!(f, "IO error: {}", err),
             EngineError::CsvError(err) => write!(f, "CSV error: {}", err),
-            EngineError::InvalidTransaction(msg) => write!(f, "Invalid transaction: {}", msg),
+            #[cfg(feature = "async")]
+            EngineError::AsyncCsvError(err) => write!(f, "CSV error: {}", err),
+            EngineError::JsonError(err) => write!(f, "JSON error: {}", err),
+            EngineError::InvalidTransaction { message, .. } => write!(f, "Invalid transaction: {}", message),
             EngineError::AccountError(msg) => write!(f, "Account error: {}", msg),
+            EngineError::AccountNotFound { .. } => write!(f, "Account error: {}", self.reason()),
+            EngineError::SchemaDrift(msg) => write!(f, "Schema drift between input files: {}", msg),
+            EngineError::InvalidSeedBalance(msg) => write!(f, "Invalid seed balance: {}", msg),
+            EngineError::OverlappingClients(msg) => write!(f, "Overlapping clients: {}", msg),
+            EngineError::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
+        }
+    }
+}
+
+impl EngineError {
+    pub fn invalid_transaction(message: impl Into<String>) -> Self {
+        EngineError::InvalidTransaction { message: message.into(), tx: None, client: None }
+    }
+
+    pub fn invalid_transaction_of(tx: u32, client: u16, message: impl Into<String>) -> Self {
+        EngineError::InvalidTransaction { message: message.into(), tx: Some(tx), client: Some(client) }
+    }
+
+    // The error message, without the kind of error it is prefixed with when displayed
+    pub fn reason(&self) -> String {
+        match self {
+            EngineError::InvalidTransaction { message: msg, .. }
+            | EngineError::AccountError(msg)
+            | EngineError::SchemaDrift(msg)
+            | EngineError::InvalidSeedBalance(msg)
+            | EngineError::OverlappingClients(msg)
+            | EngineError::InvalidConfig(msg) => msg.clone(),
+            EngineError::AccountNotFound { client, tx } =>
+                format!("Account of client {} not found for transaction {}", client, tx),
+            _ => self.to_string(),
         }
     }
 }
@@ -34,8 +85,48 @@ impl From<csv::Error> for EngineError {
     }
 }
 
+#[cfg(feature = "async")]
+impl From<csv_async::Error> for EngineError {
+    fn from(err: csv_async::Error) -> Self {
+        EngineError::AsyncCsvError(err)
+    }
+}
+
+impl From<serde_json::Error> for EngineError {
+    fn from(err: serde_json::Error) -> Self {
+        EngineError::JsonError(err)
+    }
+}
+
 impl From<AccountError> for EngineError {
     fn from(err: AccountError) -> Self {
         EngineError::AccountError(err.as_str().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each conversion is used through `?`, the way the engine relies on them
+    fn convert<E>(err: E) -> Result<(), EngineError> where EngineError: From<E> {
+        Err(err)?
+    }
+
+    #[test]
+    fn test_from_conversions() {
+        let io_error = std::io::Error::other("disk full");
+        assert!(matches!(convert(io_error), Err(EngineError::IoError(_))));
+
+        let csv_error = csv::Reader::from_reader("a,b\n1\n".as_bytes()).records()
+            .find_map(Result::err)
+            .unwrap();
+        assert!(matches!(convert(csv_error), Err(EngineError::CsvError(_))));
+
+        let json_error = serde_json::from_str::<u32>("x").unwrap_err();
+        assert!(matches!(convert(json_error), Err(EngineError::JsonError(_))));
+
+        let error = convert(AccountError::InsufficientFunds).unwrap_err();
+        assert_eq!(error.to_string(), "Account error: Insufficient funds");
+    }
+}
@@ -0,0 +1,8 @@
+pub mod account;
+pub mod amount;
+pub mod config;
+pub mod engine;
+pub mod engine_error;
+pub mod server;
+mod snapshot;
+pub mod transaction;
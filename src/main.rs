@@ -1,41 +1,616 @@
 use std::env;
 use std::process;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-mod engine;
-mod account;
-mod transaction;
-mod engine_error;
+use sha2::{Digest, Sha256};
 
-use engine::TransactionEngine;
-use engine_error::EngineError;
+use transactions_engine::account::AccountRecord;
+use transactions_engine::config::EngineConfig;
+use transactions_engine::engine::{
+    self, BoolFormat, ErrorFormat, OutputSort, SeedDuplicatePolicy, TransactionEngine, TransactionEngineBuilder,
+};
+use transactions_engine::engine_error::EngineError;
+use transactions_engine::server;
 
 
-fn main() -> Result<(), EngineError> {
+struct Options {
+    input_files: Vec<String>,
+    strict: bool,
+    lenient_amounts: bool,
+    lock_changelog_file: Option<String>,
+    allow_schema_drift: bool,
+    timeseries_file: Option<String>,
+    client: Option<u16>,
+    seed_balances_file: Option<String>,
+    seed_duplicate_policy: SeedDuplicatePolicy,
+    trailing_checksum: bool,
+    resume_file: Option<String>,
+    save_file: Option<String>,
+    control_file: Option<String>,
+    internal_precision: Option<u32>,
+    output_precision: Option<u32>,
+    chronological: bool,
+    output_file: Option<String>,
+    error_format: ErrorFormat,
+    fail_on_reject: bool,
+    history_window: Option<usize>,
+    signed_amounts: bool,
+    quiet: bool,
+    verbose: bool,
+    pretty: bool,
+    thousands_separators: bool,
+    clients: Option<ClientFilter>,
+    ledger_file: Option<String>,
+    parallel_files: bool,
+    skip_zero: bool,
+    monotonic_tx_ids: bool,
+    withdrawal_disputes: bool,
+    progress: bool,
+    bool_format: BoolFormat,
+    output_sort: OutputSort,
+    minor_units: Option<u32>,
+    totals: bool,
+    trim: csv::Trim,
+    allow_truncated: bool,
+    until: Option<u32>,
+    disputes_file: Option<String>,
+    config_file: Option<String>,
+}
+
+// Client ids and inclusive ranges of ids selected for the output, e.g. 1,5,100-200
+struct ClientFilter(Vec<RangeInclusive<u16>>);
+
+impl ClientFilter {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let parse_id = |id: &str| id.trim().parse::<u16>()
+            .map_err(|_| format!("invalid client id '{}'", id.trim()));
+
+        let ranges = spec.split(',')
+            .map(|part| match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse_id(start)?, parse_id(end)?);
+                    if start > end {
+                        return Err(format!("empty client range '{}'", part.trim()));
+                    }
+                    Ok(start..=end)
+                }
+                None => parse_id(part).map(|id| id..=id),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ClientFilter(ranges))
+    }
+
+    fn contains(&self, client: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&client))
+    }
+}
+
+const USAGE: &str = "[--strict] [--lenient-amounts] [--lock-changelog <file>] [--allow-schema-drift] [--timeseries <file> --client <id>] \
+[--seed-balances <file>] [--seed-duplicate-policy error|sum|last-wins] \
+[--trailing-checksum] [--resume <state>] [--save <state>] [--control-file <path>] \
+[--internal-precision <dp>] [--output-precision <dp>] [--chronological] [--output <file>] \
+[--error-format human|json] [--fail-on-reject] [--history-window <count>] \
+[--signed-amounts] [--quiet] [--verbose] [--pretty] [--thousands-separators] [--clients <ids>] [--ledger <file>] [--parallel-files] [--skip-zero] [--monotonic-tx-ids] [--withdrawal-disputes] [--progress] [--bool-format text|numeric|yes-no] [--sort client|total] [--minor-units <scale>] [--totals] [--trim all|headers|fields|none] [--allow-truncated] [--until <tx>] [--disputes <file>] [--config <file>] <input.csv>...";
+
+const SERVER_USAGE: &str = "server [--config <file>] [--strict] [--lenient-amounts] [--allow-schema-drift] \
+[--control-file <path>] [--internal-precision <dp>] [--output-precision <dp>] [--error-format human|json] \
+[--history-window <count>] [--signed-amounts] [--verbose] [--thousands-separators] [--monotonic-tx-ids] \
+[--withdrawal-disputes] [--progress] [--bool-format text|numeric|yes-no] [--sort client|total] [--minor-units <scale>] \
+[--trim all|headers|fields|none] [--allow-truncated] [--until <tx>] <address>";
+
+// Exit codes of the binary, documented in the README
+const USAGE_EXIT_CODE: i32 = 1;
+const IO_ERROR_EXIT_CODE: i32 = 2;
+const PARSE_ERROR_EXIT_CODE: i32 = 3;
+const ENGINE_ERROR_EXIT_CODE: i32 = 4;
+// A run that completed, but rejected some transactions with --fail-on-reject
+const REJECTED_TRANSACTIONS_EXIT_CODE: i32 = 5;
+
+// How often the number of processed rows is logged with --progress
+const PROGRESS_INTERVAL_ROWS: usize = 100_000;
+
+// Stop flag of the engine, set on SIGINT so that the balances processed so far are still written
+static INTERRUPT_FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// How often the control file is checked while processing is paused
+const CONTROL_FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    
-    if args.len() != 2 {
-        eprintln!("Usage: {} <input.csv>", args[0]);
-        process::exit(1);
+
+    if args.get(1).is_some_and(|arg| arg == "server") {
+        let Some(options) = parse_args(&args[2..]).filter(is_server_options) else {
+            eprintln!("Usage: {} {}", args[0], SERVER_USAGE);
+            process::exit(USAGE_EXIT_CODE);
+        };
+        exit_on_error(run_server(&options.input_files[0], &options));
+        return;
+    }
+
+    let options = match parse_args(&args[1..]) {
+        Some(options) => options,
+        None => {
+            eprintln!("Usage: {} {}", args[0], USAGE);
+            eprintln!("       {} {}", args[0], SERVER_USAGE);
+            process::exit(USAGE_EXIT_CODE);
+        }
+    };
+
+    exit_on_error(run(&options));
+}
+
+fn exit_on_error(result: Result<(), EngineError>) {
+    if let Err(err) = result {
+        eprintln!("Error: {:?}", err);
+        process::exit(exit_code(&err));
+    }
+}
+
+fn exit_code(err: &EngineError) -> i32 {
+    match err {
+        EngineError::IoError(_) => IO_ERROR_EXIT_CODE,
+        EngineError::CsvError(err) if err.is_io_error() => IO_ERROR_EXIT_CODE,
+        #[cfg(feature = "async")]
+        EngineError::AsyncCsvError(err) if err.is_io_error() => IO_ERROR_EXIT_CODE,
+        #[cfg(feature = "async")]
+        EngineError::AsyncCsvError(_) => PARSE_ERROR_EXIT_CODE,
+        EngineError::CsvError(_)
+        | EngineError::JsonError(_)
+        | EngineError::SchemaDrift(_)
+        | EngineError::InvalidSeedBalance(_)
+        | EngineError::InvalidConfig(_) => PARSE_ERROR_EXIT_CODE,
+        EngineError::InvalidTransaction { .. }
+        | EngineError::AccountError(_)
+        | EngineError::AccountNotFound { .. }
+        | EngineError::OverlappingClients(_) => ENGINE_ERROR_EXIT_CODE,
+    }
+}
+
+// The server takes the engine flags and a single address in place of the input files. The flags about the inputs,
+// outputs and state files of a batch run don't apply to it.
+fn is_server_options(options: &Options) -> bool {
+    options.input_files.len() == 1
+        && !options.chronological
+        && !options.parallel_files
+        && !options.fail_on_reject
+        && !options.quiet
+        && !options.pretty
+        && !options.totals
+        && !options.skip_zero
+        && !options.trailing_checksum
+        && options.clients.is_none()
+        && options.output_file.is_none()
+        && options.lock_changelog_file.is_none()
+        && options.timeseries_file.is_none()
+        && options.ledger_file.is_none()
+        && options.disputes_file.is_none()
+        && options.seed_balances_file.is_none()
+        && options.resume_file.is_none()
+        && options.save_file.is_none()
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut input_files = Vec::new();
+    let mut strict = false;
+    let mut lenient_amounts = false;
+    let mut lock_changelog_file = None;
+    let mut allow_schema_drift = false;
+    let mut timeseries_file = None;
+    let mut client = None;
+    let mut seed_balances_file = None;
+    let mut seed_duplicate_policy = SeedDuplicatePolicy::default();
+    let mut trailing_checksum = false;
+    let mut resume_file = None;
+    let mut save_file = None;
+    let mut control_file = None;
+    let mut internal_precision = None;
+    let mut output_precision = None;
+    let mut chronological = false;
+    let mut output_file = None;
+    let mut error_format = ErrorFormat::default();
+    let mut fail_on_reject = false;
+    let mut history_window = None;
+    let mut signed_amounts = false;
+    let mut quiet = false;
+    let mut verbose = false;
+    let mut pretty = false;
+    let mut thousands_separators = false;
+    let mut clients = None;
+    let mut ledger_file = None;
+    let mut parallel_files = false;
+    let mut skip_zero = false;
+    let mut monotonic_tx_ids = false;
+    let mut withdrawal_disputes = false;
+    let mut progress = false;
+    let mut bool_format = BoolFormat::default();
+    let mut output_sort = OutputSort::default();
+    let mut minor_units = None;
+    let mut totals = false;
+    let mut trim = csv::Trim::All;
+    let mut allow_truncated = false;
+    let mut until = None;
+    let mut disputes_file = None;
+    let mut config_file = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strict" => strict = true,
+            "--lenient-amounts" => lenient_amounts = true,
+            "--lock-changelog" => lock_changelog_file = Some(args.next()?.clone()),
+            "--allow-schema-drift" => allow_schema_drift = true,
+            "--timeseries" => timeseries_file = Some(args.next()?.clone()),
+            "--ledger" => ledger_file = Some(args.next()?.clone()),
+            "--disputes" => disputes_file = Some(args.next()?.clone()),
+            "--config" => config_file = Some(args.next()?.clone()),
+            "--parallel-files" => parallel_files = true,
+            "--skip-zero" => skip_zero = true,
+            "--totals" => totals = true,
+            "--allow-truncated" => allow_truncated = true,
+            "--monotonic-tx-ids" => monotonic_tx_ids = true,
+            "--withdrawal-disputes" => withdrawal_disputes = true,
+            "--progress" => progress = true,
+            "--until" => until = Some(args.next()?.parse().ok()?),
+            "--minor-units" => minor_units = Some(args.next()?.parse().ok()?),
+            "--client" => client = Some(args.next()?.parse().ok()?),
+            "--seed-balances" => seed_balances_file = Some(args.next()?.clone()),
+            "--seed-duplicate-policy" => {
+                seed_duplicate_policy = match args.next()?.as_str() {
+                    "error" => SeedDuplicatePolicy::Error,
+                    "sum" => SeedDuplicatePolicy::Sum,
+                    "last-wins" => SeedDuplicatePolicy::LastWins,
+                    _ => return None,
+                }
+            }
+            "--trailing-checksum" => trailing_checksum = true,
+            "--chronological" => chronological = true,
+            "--resume" => resume_file = Some(args.next()?.clone()),
+            "--save" => save_file = Some(args.next()?.clone()),
+            "--control-file" => control_file = Some(args.next()?.clone()),
+            "--internal-precision" => internal_precision = Some(args.next()?.parse().ok()?),
+            "--output" => output_file = Some(args.next()?.clone()),
+            "--output-precision" => output_precision = Some(args.next()?.parse().ok()?),
+            "--fail-on-reject" => fail_on_reject = true,
+            "--history-window" => history_window = Some(args.next()?.parse().ok()?),
+            "--signed-amounts" => signed_amounts = true,
+            "--quiet" => quiet = true,
+            "--verbose" => verbose = true,
+            "--pretty" => pretty = true,
+            "--thousands-separators" => thousands_separators = true,
+            "--clients" => {
+                let spec = args.next()?;
+                match ClientFilter::parse(spec) {
+                    Ok(filter) => clients = Some(filter),
+                    Err(err) => {
+                        eprintln!("Invalid --clients '{}': {}", spec, err);
+                        return None;
+                    }
+                }
+            }
+            "--bool-format" => {
+                bool_format = match args.next()?.as_str() {
+                    "text" => BoolFormat::Text,
+                    "numeric" => BoolFormat::Numeric,
+                    "yes-no" => BoolFormat::YesNo,
+                    _ => return None,
+                }
+            }
+            "--trim" => {
+                trim = match args.next()?.as_str() {
+                    "all" => csv::Trim::All,
+                    "headers" => csv::Trim::Headers,
+                    "fields" => csv::Trim::Fields,
+                    "none" => csv::Trim::None,
+                    _ => return None,
+                }
+            }
+            "--sort" => {
+                output_sort = match args.next()?.as_str() {
+                    "client" => OutputSort::Client,
+                    "total" => OutputSort::Total,
+                    _ => return None,
+                }
+            }
+            "--error-format" => {
+                error_format = match args.next()?.as_str() {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    _ => return None,
+                }
+            }
+            _ if arg.starts_with("--") => return None,
+            _ => input_files.push(arg.clone()),
+        }
+    }
+
+    // A timeseries is only produced for a single client
+    if input_files.is_empty() || (timeseries_file.is_some() && client.is_none()) {
+        return None;
+    }
+
+    Some(Options {
+        input_files,
+        strict,
+        lenient_amounts,
+        lock_changelog_file,
+        allow_schema_drift,
+        timeseries_file,
+        client,
+        seed_balances_file,
+        seed_duplicate_policy,
+        trailing_checksum,
+        resume_file,
+        save_file,
+        control_file,
+        internal_precision,
+        output_precision,
+        chronological,
+        output_file,
+        error_format,
+        fail_on_reject,
+        history_window,
+        signed_amounts,
+        quiet,
+        verbose,
+        pretty,
+        thousands_separators,
+        clients,
+        ledger_file,
+        parallel_files,
+        skip_zero,
+        monotonic_tx_ids,
+        withdrawal_disputes,
+        progress,
+        bool_format,
+        output_sort,
+        minor_units,
+        totals,
+        trim,
+        allow_truncated,
+        until,
+        disputes_file,
+        config_file,
+    })
+}
+
+// Engine configured from the config file and the command line flags, shared by the batch and server modes
+fn engine_builder(options: &Options) -> Result<TransactionEngineBuilder, EngineError> {
+    let mut builder = TransactionEngine::builder();
+    if let Some(config_file) = &options.config_file {
+        builder = EngineConfig::from_toml(&fs::read_to_string(config_file)?)?.apply(builder);
+    }
+
+    // Flags given on the command line override the config file
+    if options.strict {
+        builder = builder.strict(true);
+    }
+    if options.lenient_amounts {
+        builder = builder.lenient_amounts(true);
+    }
+    if options.monotonic_tx_ids {
+        builder = builder.monotonic_tx_ids(true);
+    }
+    if options.withdrawal_disputes {
+        builder = builder.withdrawal_disputes(true);
     }
+    builder = builder
+        .allow_schema_drift(options.allow_schema_drift)
+        .timeseries_client(options.timeseries_file.as_ref().and(options.client))
+        .ledger(options.ledger_file.is_some())
+        .bool_format(options.bool_format)
+        .output_sort(options.output_sort)
+        .trim(options.trim)
+        .allow_truncated(options.allow_truncated)
+        .seed_duplicate_policy(options.seed_duplicate_policy)
+        .chronological_order(options.chronological)
+        .error_format(options.error_format)
+        .signed_amounts(options.signed_amounts)
+        .verbose(options.verbose)
+        .thousands_separators(options.thousands_separators);
 
-    let input_file = &args[1];
-    run(input_file)
+    if let Some(control_file) = &options.control_file {
+        builder = builder.control_file(control_file, CONTROL_FILE_POLL_INTERVAL);
+    }
+    if let Some(internal_precision) = options.internal_precision {
+        builder = builder.internal_precision(internal_precision);
+    }
+    if let Some(output_precision) = options.output_precision {
+        builder = builder.output_precision(output_precision);
+    }
+    if let Some(minor_units) = options.minor_units {
+        builder = builder.minor_units(minor_units);
+    }
+    if let Some(history_window) = options.history_window {
+        builder = builder.history_window(history_window);
+    }
+    if let Some(until) = options.until {
+        builder = builder.stop_after_tx(until);
+    }
+    if options.progress {
+        builder = builder.progress_interval(PROGRESS_INTERVAL_ROWS);
+    }
+    Ok(builder)
 }
 
-fn run(input_file: &String) -> Result<(), EngineError> {
-    let mut engine = TransactionEngine::new();
+fn run(options: &Options) -> Result<(), EngineError> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    install_interrupt_handler(Arc::clone(&interrupted));
+    let mut engine = engine_builder(options)?.stop_flag(Arc::clone(&interrupted)).build();
+
+    if let Some(resume_file) = &options.resume_file {
+        engine.load_snapshot(File::open(resume_file)?)?;
+    }
+
+    if let Some(seed_balances_file) = &options.seed_balances_file {
+        let mut seed_rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(seed_balances_file)?;
+
+        engine.seed_balances_from_reader(&mut seed_rdr)?;
+    }
+
+    // Input files are processed in order, as a single stream of transactions, unless their clients are disjoint
+    if options.parallel_files {
+        engine.process_disjoint_files(&options.input_files)?;
+    } else {
+        for input_file in options.input_files.iter().take_while(|_| !interrupted.load(Ordering::Relaxed)) {
+            let mut rdr = engine.input_reader(engine::open_input_file(input_file)?);
+
+            engine.process_transactions_from_reader(&mut rdr)?;
+        }
+    }
+    engine.finish()?;
+
+    if interrupted.load(Ordering::Relaxed) {
+        options.error_format.log("Interrupted, writing the balances processed so far");
+    }
+
+    if let Some(save_file) = &options.save_file {
+        engine.save_snapshot(File::create(save_file)?)?;
+    }
+
+    let mut records = engine.account_records();
+    if let Some(clients) = &options.clients {
+        records.retain(|record| clients.contains(record.client));
+    }
+    if options.skip_zero {
+        records.retain(|record| !record.is_zero_balance());
+    }
+
+    let mut output = Vec::new();
+    if options.pretty {
+        output = pretty_balances(&records, options.bool_format).into_bytes();
+    } else {
+        let mut wtr = csv::Writer::from_writer(&mut output);
+        match options.minor_units {
+            Some(scale) => engine::write_account_records_in_minor_units(&records, scale, options.bool_format, &mut wtr)?,
+            None => engine::write_account_records(&records, options.bool_format, &mut wtr)?,
+        }
+        if options.totals {
+            engine::write_totals_row(&records, options.minor_units, &mut wtr)?;
+        }
+    }
+
+    if options.trailing_checksum {
+        append_checksum(&mut output);
+    }
+
+    match &options.output_file {
+        Some(output_file) => File::create(output_file)?.write_all(&output)?,
+        None => std::io::stdout().write_all(&output)?,
+    }
+
+    if let Some(lock_changelog_file) = &options.lock_changelog_file {
+        let mut changelog_wtr = csv::Writer::from_path(lock_changelog_file)?;
+        engine.output_lock_changelog_to_writer(&mut changelog_wtr)?;
+    }
+
+    if let Some(timeseries_file) = &options.timeseries_file {
+        let mut timeseries_wtr = csv::Writer::from_path(timeseries_file)?;
+        engine.output_timeseries_to_writer(&mut timeseries_wtr)?;
+    }
 
-    let file = File::open(input_file)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(file);
+    if let Some(ledger_file) = &options.ledger_file {
+        let mut ledger_wtr = csv::Writer::from_path(ledger_file)?;
+        engine.output_ledger_to_writer(&mut ledger_wtr)?;
+    }
 
-    engine.process_transactions_from_reader(&mut rdr)?;
+    if let Some(disputes_file) = &options.disputes_file {
+        let mut disputes_wtr = csv::Writer::from_path(disputes_file)?;
+        engine.output_open_disputes_to_writer(&mut disputes_wtr)?;
+    }
 
-    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    let stats = engine.stats();
+    if !options.quiet {
+        match options.error_format {
+            ErrorFormat::Human => eprintln!("{}", stats),
+            ErrorFormat::Json => eprintln!("{}", serde_json::to_string(&stats)?),
+        }
+    }
 
-    engine.output_account_balances_to_writer(&mut wtr)?;
+    if options.fail_on_reject && stats.rejected > 0 {
+        process::exit(REJECTED_TRANSACTIONS_EXIT_CODE);
+    }
 
     Ok(())
 }
+
+#[cfg(unix)]
+fn install_interrupt_handler(flag: Arc<AtomicBool>) {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        if let Some(flag) = INTERRUPT_FLAG.get() {
+            flag.store(true, Ordering::Relaxed);
+        }
+        // A second interrupt terminates the process as usual
+        // SAFETY: signal is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+
+    if INTERRUPT_FLAG.set(flag).is_ok() {
+        // SAFETY: the handler only uses an atomic and signal, which are async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGINT, on_interrupt as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler(_flag: Arc<AtomicBool>) {}
+
+fn run_server(address: &str, options: &Options) -> Result<(), EngineError> {
+    let engine_builder = engine_builder(options)?;
+    let listener = TcpListener::bind(address)?;
+    println!("Listening on {}", listener.local_addr()?);
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    install_interrupt_handler(Arc::clone(&interrupted));
+    let engine = engine_builder.stop_flag(Arc::clone(&interrupted)).build();
+
+    // Only returns once interrupted, with the balances processed so far written
+    server::serve(listener, engine, &interrupted, std::io::stdout())
+}
+
+// Format the balances as a table for the terminal, with right-aligned columns
+fn pretty_balances(records: &[AccountRecord], bool_format: BoolFormat) -> String {
+    let rows: Vec<[String; 5]> = records.iter()
+        .map(|record| [
+            record.client.to_string(),
+            record.available.to_string(),
+            record.held.to_string(),
+            record.total.to_string(),
+            bool_format.format(record.locked).to_string(),
+        ])
+        .collect();
+
+    let mut widths = AccountRecord::HEADERS.map(str::len);
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(AccountRecord::HEADERS.map(String::from)).chain(rows) {
+        let fields: Vec<_> = row.iter().zip(widths)
+            .map(|(field, width)| format!("{:>width$}", field, width = width))
+            .collect();
+        table.push_str(&fields.join("  "));
+        table.push('\n');
+    }
+    table
+}
+
+// Append a comment line with the SHA-256 of all the preceding output bytes
+fn append_checksum(output: &mut Vec<u8>) {
+    let checksum = Sha256::digest(&output);
+    output.extend_from_slice(format!("#sha256:{:x}\n", checksum).as_bytes());
+}
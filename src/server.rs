@@ -0,0 +1,88 @@
+use crate::engine::TransactionEngine;
+use crate::engine_error::EngineError;
+use std::io::{ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Control line making the server write the current balances back on the connection
+const DUMP_COMMAND: &str = "DUMP";
+
+// How often the stop flag is checked while waiting for connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Accept connections until the listener fails or the stop flag is set, every connection feeds the same engine.
+// A connection sends a CSV header line first, then one transaction per line. Once stopped, the balances
+// processed so far are written to the output, without waiting for the connections still open.
+pub fn serve<W: Write>(
+    listener: TcpListener,
+    engine: TransactionEngine,
+    stop_flag: &AtomicBool,
+    output: W,
+) -> Result<(), EngineError> {
+    let error_format = engine.error_format();
+    let engine = Arc::new(Mutex::new(engine));
+    listener.set_nonblocking(true)?;
+
+    while !stop_flag.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        stream.set_nonblocking(false)?;
+        let engine = Arc::clone(&engine);
+
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &engine) {
+                error_format.log(format_args!("Closing connection after error: {}", err));
+            }
+        });
+    }
+
+    let mut wtr = csv::Writer::from_writer(output);
+    engine.lock().unwrap().output_account_balances_to_writer(&mut wtr)?;
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, engine: &Mutex<TransactionEngine>) -> Result<(), EngineError> {
+    let mut writer = stream.try_clone()?;
+    // Read like an input file, one reader for the whole connection. Flexible, as the control lines have a
+    // single field.
+    let mut reader = engine.lock().unwrap().input_reader_builder().flexible(true).from_reader(stream);
+
+    // Headers differing from those of the previous connections close the connection, as schema drift does the input
+    let headers = reader.headers()?.clone();
+    match engine.lock().unwrap().start_record_input(&headers) {
+        Ok(true) => {}
+        Ok(false) => return Ok(()),
+        Err(err) => {
+            writeln!(writer, "Error: {}", err)?;
+            return Err(err);
+        }
+    }
+
+    for (row, record) in reader.records().enumerate() {
+        let result = record.map_err(EngineError::from).and_then(|record| {
+            if record.len() == 1 && record[0].trim() == DUMP_COMMAND {
+                let mut wtr = csv::Writer::from_writer(&mut writer);
+                engine.lock().unwrap().output_account_balances_to_writer(&mut wtr)?;
+                wtr.flush()?;
+                Ok(())
+            } else {
+                engine.lock().unwrap().process_input_record(row, record, &headers)
+            }
+        });
+        if let Err(err) = result {
+            // Invalid lines are reported to the client, the connection stays open
+            writeln!(writer, "Error: {}", err)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use crate::account::Account;
+use crate::transaction::Transaction;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Account serialization used for snapshots, unlike the CSV output it includes the dispute tracking
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountSnapshot {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    #[serde(default)]
+    frozen: bool,
+    #[serde(default)]
+    currency: Option<String>,
+    disputed_transactions: HashMap<u32, Decimal>,
+    // Missing from older snapshots, taken as the held amounts then
+    #[serde(default)]
+    disputed_amounts: HashMap<u32, Decimal>,
+    resolved_transactions: HashSet<u32>,
+    #[serde(default)]
+    finalized_transactions: HashSet<u32>,
+    #[serde(default)]
+    disputed_withdrawals: HashSet<u32>,
+    #[serde(default)]
+    failed_withdrawals: u32,
+}
+
+impl From<&Account> for AccountSnapshot {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            frozen: account.frozen,
+            currency: account.currency.clone(),
+            disputed_transactions: account.disputed_transactions.clone(),
+            disputed_amounts: account.disputed_amounts.clone(),
+            resolved_transactions: account.resolved_transactions.clone(),
+            finalized_transactions: account.finalized_transactions.clone(),
+            disputed_withdrawals: account.disputed_withdrawals.clone(),
+            failed_withdrawals: account.failed_withdrawals,
+        }
+    }
+}
+
+impl From<AccountSnapshot> for Account {
+    fn from(snapshot: AccountSnapshot) -> Self {
+        let mut account = Account::new(snapshot.client);
+        account.available = snapshot.available;
+        account.held = snapshot.held;
+        account.total = snapshot.total;
+        account.locked = snapshot.locked;
+        account.frozen = snapshot.frozen;
+        account.currency = snapshot.currency;
+        account.disputed_amounts = if snapshot.disputed_amounts.is_empty() {
+            snapshot.disputed_transactions.clone()
+        } else {
+            snapshot.disputed_amounts
+        };
+        account.disputed_transactions = snapshot.disputed_transactions;
+        account.resolved_transactions = snapshot.resolved_transactions;
+        account.finalized_transactions = snapshot.finalized_transactions;
+        account.disputed_withdrawals = snapshot.disputed_withdrawals;
+        account.failed_withdrawals = snapshot.failed_withdrawals;
+        account
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EngineSnapshot {
+    accounts: Vec<AccountSnapshot>,
+    transaction_history: Vec<Transaction>,
+    rejected_transactions: Vec<Transaction>,
+    // Ids of the history in insertion order, which a history window evicts from
+    #[serde(default)]
+    history_order: Vec<u32>,
+    #[serde(default)]
+    pruned_transactions: HashSet<u32>,
+    // Highest tx id of the deposits and withdrawals seen, for monotonic tx ids
+    #[serde(default)]
+    last_tx: Option<u32>,
+    // Disputes, resolves and chargebacks waiting for their transaction
+    #[serde(default)]
+    deferred_transactions: Vec<Transaction>,
+}
+
+// Engine state saved in a snapshot
+pub(crate) struct EngineState {
+    pub(crate) accounts: HashMap<u16, Account>,
+    pub(crate) transaction_history: HashMap<u32, Transaction>,
+    pub(crate) rejected_transactions: HashMap<u32, Transaction>,
+    // Empty when the snapshot was saved without a history window, or by a version not saving it
+    pub(crate) history_order: VecDeque<u32>,
+    pub(crate) pruned_transactions: HashSet<u32>,
+    pub(crate) last_tx: Option<u32>,
+    pub(crate) deferred_transactions: Vec<Transaction>,
+}
+
+impl EngineSnapshot {
+    pub(crate) fn new(
+        accounts: &HashMap<u16, Account>,
+        transaction_history: &HashMap<u32, Transaction>,
+        rejected_transactions: &HashMap<u32, Transaction>,
+        history_order: &VecDeque<u32>,
+        pruned_transactions: &HashSet<u32>,
+        last_tx: Option<u32>,
+        deferred_transactions: &[Transaction],
+    ) -> Self {
+        Self {
+            accounts: accounts.values().map(AccountSnapshot::from).collect(),
+            transaction_history: transaction_history.values().cloned().collect(),
+            rejected_transactions: rejected_transactions.values().cloned().collect(),
+            history_order: history_order.iter().copied().collect(),
+            pruned_transactions: pruned_transactions.clone(),
+            last_tx,
+            deferred_transactions: deferred_transactions.to_vec(),
+        }
+    }
+
+    pub(crate) fn into_state(self) -> EngineState {
+        let accounts = self.accounts.into_iter()
+            .map(|snapshot| (snapshot.client, Account::from(snapshot)))
+            .collect();
+        let transaction_history: HashMap<_, _> = self.transaction_history.into_iter()
+            .map(|transaction| (transaction.tx, transaction))
+            .collect();
+        let rejected_transactions: HashMap<_, _> = self.rejected_transactions.into_iter()
+            .map(|transaction| (transaction.tx, transaction))
+            .collect();
+        // Older snapshots without it still hold the ids of every deposit and withdrawal seen
+        let last_tx = self.last_tx.or_else(|| {
+            transaction_history.keys()
+                .chain(rejected_transactions.keys())
+                .chain(&self.pruned_transactions)
+                .max()
+                .copied()
+        });
+
+        EngineState {
+            accounts,
+            transaction_history,
+            rejected_transactions,
+            history_order: self.history_order.into(),
+            pruned_transactions: self.pruned_transactions,
+            last_tx,
+            deferred_transactions: self.deferred_transactions,
+        }
+    }
+}
@@ -1,7 +1,11 @@
+use crate::engine_error::EngineError;
+use csv::StringRecord;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -9,15 +13,82 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    // Resolves every open dispute of the client, the tx id doesn't reference any transaction
+    ResolveAll,
+    // Administrative freeze of the client's account, the tx id doesn't reference any transaction
+    Freeze,
+    Unfreeze,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl TransactionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::ResolveAll => "resolveall",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unfreeze => "unfreeze",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "TransactionRow")]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
     pub client: u16,
     pub tx: u32,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<Decimal>,
+    // Unix timestamp in seconds, the column is optional
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    // Currency code such as USD, the column is optional
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+// The tx id is optional in the input for resolve all rows, which don't reference any transaction
+#[derive(Deserialize)]
+struct TransactionRow {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    #[serde(default)]
+    tx: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    amount: Option<Decimal>,
+    #[serde(default)]
+    timestamp: Option<i64>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+impl TryFrom<TransactionRow> for Transaction {
+    type Error = String;
+
+    fn try_from(row: TransactionRow) -> Result<Self, Self::Error> {
+        let tx = match (row.tx, &row.transaction_type) {
+            (Some(tx), _) => tx,
+            (None, TransactionType::ResolveAll) => 0,
+            (None, transaction_type) => {
+                return Err(format!("missing tx id, {} rows must have one", transaction_type.as_str()));
+            }
+        };
+
+        Ok(Transaction {
+            transaction_type: row.transaction_type,
+            client: row.client,
+            tx,
+            amount: row.amount,
+            timestamp: row.timestamp,
+            currency: row.currency,
+        })
+    }
 }
 
 impl Transaction {
@@ -28,8 +99,251 @@ impl Transaction {
     pub fn is_dispute_related(&self) -> bool {
         matches!(
             self.transaction_type,
-            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback | TransactionType::ResolveAll
         )
     }
+
+    pub fn is_freeze_related(&self) -> bool {
+        matches!(self.transaction_type, TransactionType::Freeze | TransactionType::Unfreeze)
+    }
+
+    // Client credited by a transaction involving a second account. None for every current type, which only
+    // applies to the account of its client column; a transfer type would return its target here.
+    pub fn target(&self) -> Option<u16> {
+        None
+    }
+
+    // A multi-party transaction to its own client would debit and re-credit the same account
+    fn check_target(&self, target: Option<u16>) -> Result<(), EngineError> {
+        if target == Some(self.client) {
+            return Err(EngineError::invalid_transaction_of(self.tx, self.client, format!(
+                "Transaction {} has the same source and target client {}", self.tx, self.client,
+            )));
+        }
+        Ok(())
+    }
+
+    // Amount of a deposit or withdrawal, an error rather than a panic if validation let one through without it
+    pub fn required_amount(&self) -> Result<Decimal, EngineError> {
+        self.amount.ok_or_else(|| EngineError::invalid_transaction_of(self.tx, self.client, format!(
+            "Transaction {} has no amount", self.tx,
+        )))
+    }
+
+    // Checks that only depend on the transaction itself, the engine adds those depending on its configuration
+    // and on the transactions processed so far
+    pub fn validate(&self) -> Result<(), EngineError> {
+        // Check if transaction requires amount but doesn't have one
+        if self.requires_amount() && self.amount.is_none() {
+            return Err(EngineError::invalid_transaction_of(self.tx, self.client,
+                "Deposit and withdrawal transactions must have an amount",
+            ));
+        }
+
+        // Check if dispute-related transaction has an amount (it shouldn't, except disputes of a portion of a transaction)
+        if self.is_dispute_related()
+            && self.transaction_type != TransactionType::Dispute
+            && self.amount.is_some() {
+            return Err(EngineError::invalid_transaction_of(self.tx, self.client,
+                "Resolve, chargeback and resolve all transactions should not have an amount",
+            ));
+        }
+
+        if self.is_freeze_related() && self.amount.is_some() {
+            return Err(EngineError::invalid_transaction_of(self.tx, self.client,
+                "Freeze and unfreeze transactions should not have an amount",
+            ));
+        }
+
+        self.check_target(self.target())?;
+
+        // Check for negative amounts
+        if let Some(amount) = self.amount
+            && amount <= Decimal::ZERO {
+            return Err(EngineError::invalid_transaction_of(self.tx, self.client,
+                "Transaction amount must be positive",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+
+// Parse the amount ourselves, so that an unparseable amount is reported along with the offending value.
+// Trailing zeros are dropped (1.50 is read as 1.5), as they were when the CSV field was inferred as a number.
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+    let Some(amount) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    let invalid = || de::Error::custom(format!("invalid amount '{}', expected a decimal number", amount));
+    // Decimal accepts underscores between digits, which are only stripped as thousands separators when enabled
+    if amount.contains('_') {
+        return Err(invalid());
+    }
+
+    Decimal::from_str(&amount)
+        .or_else(|_| Decimal::from_scientific(&amount))
+        .map(|amount| Some(amount.normalize()))
+        .map_err(|_| invalid())
+}
+
+// Strip non-numeric trailing bytes (e.g. a null byte or BOM appended by some tools) from the amount field.
+// The field is left untouched if nothing numeric remains, so the parse error is still reported.
+pub fn strip_amount_trailing_bytes(record: &StringRecord, amount_index: usize) -> StringRecord {
+    map_amount_field(record, amount_index, |amount| {
+        let stripped = amount.trim_end_matches(|c: char| !c.is_ascii_digit());
+        if stripped.is_empty() { amount } else { stripped }.to_string()
+    })
+}
+
+// Remove the thousands separators (spaces, underscores and commas, in a quoted field) from the amount field.
+// The field is left untouched unless they only separate groups of three digits, so the parse error is still reported.
+pub fn strip_thousands_separators(record: &StringRecord, amount_index: usize) -> StringRecord {
+    map_amount_field(record, amount_index, |amount| {
+        without_thousands_separators(amount).unwrap_or_else(|| amount.to_string())
+    })
+}
+
+fn without_thousands_separators(amount: &str) -> Option<String> {
+    const SEPARATORS: [char; 3] = [' ', '_', ','];
+
+    let (integer, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    if fraction.contains(SEPARATORS) {
+        return None;
+    }
+    let digits = integer.trim_start_matches(['-', '+']);
+    let Some(separator) = digits.chars().find(|c| SEPARATORS.contains(c)) else {
+        return Some(amount.to_string());
+    };
+
+    // The first group has 1 to 3 digits, the others exactly 3, all separated by the same separator
+    let mut groups = digits.split(separator);
+    let is_group = |group: &str, lengths: std::ops::RangeInclusive<usize>| {
+        lengths.contains(&group.len()) && group.bytes().all(|byte| byte.is_ascii_digit())
+    };
+    if !groups.next().is_some_and(|group| is_group(group, 1..=3)) || !groups.all(|group| is_group(group, 3..=3)) {
+        return None;
+    }
+    Some(amount.replace(separator, ""))
+}
+
+fn map_amount_field(record: &StringRecord, amount_index: usize, map: impl Fn(&str) -> String) -> StringRecord {
+    let mut mapped_record: StringRecord = record.iter()
+        .enumerate()
+        .map(|(index, field)| if index == amount_index { map(field) } else { field.to_string() })
+        .collect();
+
+    // Keep the original position so parse errors still point at the right line
+    mapped_record.set_position(record.position().cloned());
+    mapped_record
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(transaction_type: TransactionType, amount: Option<&str>) -> Transaction {
+        Transaction {
+            transaction_type,
+            client: 1,
+            tx: 7,
+            amount: amount.map(|amount| Decimal::from_str(amount).unwrap()),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    fn validation_error(transaction: Transaction) -> String {
+        transaction.validate().unwrap_err().reason()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(transaction(TransactionType::Deposit, Some("1.5")).validate().is_ok());
+        assert!(transaction(TransactionType::Withdrawal, Some("0.0001")).validate().is_ok());
+        assert!(transaction(TransactionType::Dispute, None).validate().is_ok());
+        // Disputes of a portion of a transaction
+        assert!(transaction(TransactionType::Dispute, Some("1.0")).validate().is_ok());
+        assert!(transaction(TransactionType::Freeze, None).validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_missing_amount() {
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            assert_eq!(validation_error(transaction(transaction_type, None)),
+                "Deposit and withdrawal transactions must have an amount");
+        }
+    }
+
+    #[test]
+    fn test_validate_unexpected_amount() {
+        for transaction_type in [TransactionType::Resolve, TransactionType::Chargeback, TransactionType::ResolveAll] {
+            assert_eq!(validation_error(transaction(transaction_type, Some("1.0"))),
+                "Resolve, chargeback and resolve all transactions should not have an amount");
+        }
+        for transaction_type in [TransactionType::Freeze, TransactionType::Unfreeze] {
+            assert_eq!(validation_error(transaction(transaction_type, Some("1.0"))),
+                "Freeze and unfreeze transactions should not have an amount");
+        }
+    }
+
+    #[test]
+    fn test_validate_non_positive_amount() {
+        for amount in ["0", "-1.0"] {
+            assert_eq!(validation_error(transaction(TransactionType::Deposit, Some(amount))),
+                "Transaction amount must be positive");
+        }
+        assert_eq!(validation_error(transaction(TransactionType::Dispute, Some("-1.0"))),
+            "Transaction amount must be positive");
+    }
+
+    #[test]
+    fn test_check_target() {
+        let transaction = transaction(TransactionType::Deposit, Some("1.0"));
+        assert!(transaction.check_target(None).is_ok());
+        assert!(transaction.check_target(Some(2)).is_ok());
+        assert_eq!(transaction.check_target(Some(1)).unwrap_err().reason(),
+            "Transaction 7 has the same source and target client 1");
+    }
+
+    #[test]
+    fn test_validate_error_identifies_transaction() {
+        let error = transaction(TransactionType::Deposit, None).validate().unwrap_err();
+        assert!(matches!(error, EngineError::InvalidTransaction { tx: Some(7), client: Some(1), .. }));
+    }
+
+    #[test]
+    fn test_strip_thousands_separators() {
+        let strip = |amount: &str| {
+            let record = StringRecord::from(vec!["deposit", amount]);
+            strip_thousands_separators(&record, 1)[1].to_string()
+        };
+
+        assert_eq!(strip("1,000.50"), "1000.50");
+        assert_eq!(strip("-12 345 678"), "-12345678");
+        assert_eq!(strip("1_000"), "1000");
+        assert_eq!(strip("1000.5"), "1000.5");
+
+        // Anything else than groups of three digits in the integer part is left for the parser to reject
+        for malformed in ["1,5", "1_0.5", "1,0000", "1234,567", ",100", "1,000,", "1,000 000", "1,000.000_1", "1,,000"] {
+            assert_eq!(strip(malformed), malformed);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_missing_tx() {
+        let deserialize = |row: &str| {
+            let input = format!("type,client,tx,amount\n{}\n", row);
+            csv::Reader::from_reader(input.as_bytes()).deserialize::<Transaction>().next().unwrap()
+        };
+
+        let transaction = deserialize("resolveall,1,,").unwrap();
+        assert_eq!((transaction.transaction_type, transaction.client, transaction.tx), (TransactionType::ResolveAll, 1, 0));
+        assert_eq!(deserialize("resolveall,1,5,").unwrap().tx, 5);
+
+        let err = deserialize("dispute,1,,").unwrap_err();
+        assert!(err.to_string().contains("missing tx id, dispute rows must have one"), "{}", err);
+    }
+}
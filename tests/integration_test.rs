@@ -1,6 +1,10 @@
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::path::Path;
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+use sha2::{Digest, Sha256};
 
 #[test]
 fn test_transactions_basic() {
@@ -14,6 +18,7 @@ fn test_transactions_comprehensive() {
     run_success_test("repeated_dispute");
     run_success_test("repeated_chargeback");
     run_success_test("dispute_after_withdrawal");
+    run_success_test("resolve_all");
 }
 
 #[test]
@@ -45,16 +50,567 @@ fn test_invalid_tx_error() {
     run_error_test("invalid_duplicate_id");
 }
 
+#[test]
+fn test_empty_input() {
+    run_success_test("empty");
+    run_success_test_with_args("empty_with_header", "empty", &[]);
+}
+
+#[test]
+fn test_pretty_output() {
+    run_success_test_with_args("basic", "basic_pretty", &["--pretty"]);
+}
+
+#[test]
+fn test_clients_filter() {
+    run_success_test_with_args("comprehensive", "comprehensive_client_3", &["--clients", "3"]);
+    run_success_test_with_args("comprehensive", "comprehensive_clients_2_to_42", &["--clients", "2-42"]);
+    run_error_test_with_args("comprehensive", &["--clients", "1,5-"]);
+    run_error_test_with_args("comprehensive", &["--clients", "200-100"]);
+}
+
+#[test]
+fn test_skip_zero() {
+    run_success_test("zero_balances");
+    run_success_test_with_args("zero_balances", "zero_balances_skipped", &["--skip-zero"]);
+}
+
+#[test]
+fn test_byte_order_mark() {
+    // Same transactions as basic, the csv reader strips the leading UTF-8 BOM from the header
+    run_success_test_with_args("bom", "basic", &[]);
+
+    // The header of a file with a BOM matches the header of a file without one
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["tests/data/empty_with_header.csv", "tests/data/bom.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_progress() {
+    run_success_test_with_args("comprehensive", "comprehensive", &["--progress"]);
+}
+
+#[test]
+fn test_bool_format() {
+    run_success_test_with_args("comprehensive", "comprehensive", &["--bool-format", "text"]);
+    run_success_test_with_args("comprehensive", "comprehensive_numeric_bool", &["--bool-format", "numeric"]);
+    run_error_test_with_args("comprehensive", &["--bool-format", "on-off"]);
+}
+
+#[test]
+fn test_blank_dispute_amounts() {
+    // Dispute, resolve and chargeback rows with an empty or whitespace-only amount have no amount
+    run_success_test_with_args("dispute_blank_amount", "dispute_blank_amount", &["--strict"]);
+}
+
+#[test]
+fn test_until() {
+    // Balances of basic right after the deposit 3, before the withdrawals and the dispute
+    run_success_test_with_args("basic", "basic_until_3", &["--until", "3"]);
+}
+
+#[test]
+fn test_config() {
+    let config_file = temp_file("config.toml");
+    fs::write(&config_file, "version = 1\noverdraft_limit = \"5.0\"\n").unwrap();
+    run_success_test_with_args("strict_withdrawal", "strict_withdrawal_overdraft", &["--config", &config_file]);
+
+    // Command line flags override the config file
+    fs::write(&config_file, "strict = false\n").unwrap();
+    run_error_test_with_args("strict_withdrawal", &["--config", &config_file, "--strict"]);
+
+    fs::write(&config_file, "strict = true\n").unwrap();
+    run_error_test_with_args("strict_withdrawal", &["--config", &config_file]);
+    fs::remove_file(&config_file).unwrap();
+}
+
+#[test]
+fn test_verbose() {
+    // Rows of disabled types are only logged in verbose mode
+    let config_file = temp_file("verbose_config.toml");
+    fs::write(&config_file, "enabled_types = [\"deposit\"]\n").unwrap();
+    let message = "Skipping transaction 3: withdrawal transactions are disabled";
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--config", &config_file, "tests/data/strict_withdrawal.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains(message));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--config", &config_file, "--verbose", "tests/data/strict_withdrawal.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).lines().any(|line| line == message));
+    fs::remove_file(&config_file).unwrap();
+}
+
+#[test]
+fn test_gzip_input() {
+    // Compression is detected from the content, not from the file extension
+    run_success_test_with_args("gzipped", "basic", &[]);
+
+    let plain_file = temp_file("plain.csv.gz");
+    fs::copy("tests/data/basic.csv", &plain_file).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .arg(&plain_file)
+        .output()
+        .expect("Failed to execute binary");
+    fs::remove_file(&plain_file).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let expected = fs::read_to_string("tests/expected/basic.expected").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected.trim());
+}
+
+#[test]
+fn test_sort_by_total() {
+    run_success_test_with_args("comprehensive", "comprehensive_sorted_by_total", &["--sort", "total"]);
+}
+
+#[test]
+fn test_minor_units() {
+    // Some amounts of comprehensive.csv have more than 4 decimal places, which are rounded on input here
+    run_success_test_with_args("comprehensive", "comprehensive_minor_units",
+        &["--minor-units", "4", "--internal-precision", "4"]);
+    run_error_test_with_args("comprehensive", &["--minor-units", "4"]);
+    run_error_test_with_args("comprehensive", &["--minor-units", "2"]);
+}
+
+#[test]
+fn test_totals() {
+    run_success_test_with_args("basic", "basic_totals", &["--totals"]);
+}
+
+#[test]
+fn test_trim() {
+    // Same transactions as basic, with spaces after every comma
+    run_success_test_with_args("padded", "basic", &["--trim", "all"]);
+    run_error_test_with_args("padded", &["--trim", "none"]);
+}
+
+#[test]
+fn test_allow_truncated() {
+    // The last row is cut off in the middle of its type
+    run_error_test("truncated");
+    run_success_test_with_args("truncated", "truncated", &["--allow-truncated"]);
+}
+
+#[test]
+fn test_reordered_columns() {
+    // Same transactions as basic, with the columns in another order
+    run_success_test_with_args("reordered_columns", "basic", &[]);
+}
+
+#[test]
+fn test_strict_mode() {
+    // Lenient by default: the invalid withdrawal is logged and skipped
+    run_success_test("strict_withdrawal");
+    run_error_test_with_args("strict_withdrawal", &["--strict"]);
+}
+
+#[test]
+fn test_exit_codes() {
+    let exit_code = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(args)
+        .output()
+        .expect("Failed to execute binary")
+        .status
+        .code();
+
+    assert_eq!(exit_code(&[]), Some(1));
+    assert_eq!(exit_code(&["tests/data/missing.csv"]), Some(2));
+    assert_eq!(exit_code(&["tests/data/malformed.csv"]), Some(3));
+    assert_eq!(exit_code(&["--strict", "tests/data/strict_withdrawal.csv"]), Some(4));
+}
+
+#[test]
+fn test_fail_on_reject() {
+    run_success_test("strict_withdrawal");
+
+    // The balances are still written, but the exit code reports the rejected withdrawal
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--fail-on-reject", "tests/data/strict_withdrawal.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(output.status.code(), Some(5));
+
+    let expected = fs::read_to_string("tests/expected/strict_withdrawal.expected").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected.trim());
+}
+
+#[test]
+fn test_summary() {
+    let summary = "processed 4 transactions, 3 applied, 1 rejected, 2 accounts";
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .arg("tests/data/strict_withdrawal.csv")
+        .output()
+        .expect("Failed to execute binary");
+    assert!(String::from_utf8_lossy(&output.stderr).lines().any(|line| line == summary));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--quiet", "tests/data/strict_withdrawal.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(!String::from_utf8_lossy(&output.stderr).contains(summary));
+}
+
+#[test]
+fn test_lenient_amounts() {
+    // The second deposit amount ends with a null byte
+    run_error_test("lenient_amount");
+    run_success_test_with_args("lenient_amount", "lenient_amount", &["--lenient-amounts"]);
+}
+
+#[test]
+fn test_lock_changelog() {
+    let changelog_file = temp_file("lock_changelog.csv");
+    run_success_test_with_args("account_locked", "account_locked", &["--lock-changelog", &changelog_file]);
+
+    let changelog = fs::read_to_string(&changelog_file)
+        .expect("Failed to read lock changelog file");
+    fs::remove_file(&changelog_file).unwrap();
+    assert_eq!(changelog.trim(), "client,tx,locked\n1,1,true");
+}
+
+#[test]
+fn test_schema_drift() {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["tests/data/basic.csv", "tests/data/schema_drift.csv"])
+        .output()
+        .expect("Failed to execute binary");
+
+    assert!(!output.status.success(), "Expected binary to fail on schema drift");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("SchemaDrift") && stderr.contains("expected headers [type,client,tx,amount] but found [client,type,amount,tx]"),
+        "Unexpected error output: {}", stderr);
+
+    // Columns are matched by name, so the reordered file is still processed correctly when drift is allowed
+    run_success_test_with_args("schema_drift", "schema_drift", &["--allow-schema-drift", "tests/data/basic.csv"]);
+}
+
+#[test]
+fn test_timeseries() {
+    let timeseries_file = temp_file("timeseries.csv");
+    run_success_test_with_args("basic", "basic", &["--timeseries", &timeseries_file, "--client", "1"]);
+
+    let timeseries = fs::read_to_string(&timeseries_file)
+        .expect("Failed to read timeseries file");
+    fs::remove_file(&timeseries_file).unwrap();
+    assert_eq!(timeseries.trim(), "tx,type,available,held,total
+1,deposit,1,0,1
+3,deposit,3,0,3
+4,withdrawal,1.5,0,1.5
+1,dispute,0.5,1,1.5
+1,resolve,1.5,0,1.5");
+}
+
+#[test]
+fn test_ledger() {
+    let ledger_file = temp_file("ledger.csv");
+    run_success_test_with_args("basic", "basic", &["--ledger", &ledger_file]);
+
+    let ledger = fs::read_to_string(&ledger_file)
+        .expect("Failed to read ledger file");
+    fs::remove_file(&ledger_file).unwrap();
+    let expected = fs::read_to_string("tests/expected/basic_ledger.expected")
+        .expect("Failed to read expected ledger file");
+    assert_eq!(ledger.trim(), expected.trim());
+}
+
+#[test]
+fn test_open_disputes() {
+    let disputes_file = temp_file("disputes.csv");
+    run_success_test_with_args("comprehensive", "comprehensive", &["--disputes", &disputes_file]);
+
+    let disputes = fs::read_to_string(&disputes_file)
+        .expect("Failed to read disputes file");
+    fs::remove_file(&disputes_file).unwrap();
+    assert_eq!(disputes.trim(), "client,tx,held
+3,10,100");
+}
+
+#[test]
+fn test_seed_balances() {
+    let seed_args = ["--seed-balances", "tests/data/seed_balances_duplicate.csv"];
+    run_error_test_with_args("basic", &seed_args);
+    run_error_test_with_args("basic", &[&seed_args[..], &["--seed-duplicate-policy", "error"]].concat());
+    run_success_test_with_args("basic", "basic_seed_sum",
+        &[&seed_args[..], &["--seed-duplicate-policy", "sum"]].concat());
+    run_success_test_with_args("basic", "basic_seed_last_wins",
+        &[&seed_args[..], &["--seed-duplicate-policy", "last-wins"]].concat());
+}
+
+#[test]
+fn test_trailing_checksum() {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--trailing-checksum", "tests/data/basic.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (data, checksum_line) = stdout.trim_end().rsplit_once('\n').unwrap();
+    let expected = fs::read_to_string("tests/expected/basic.expected").unwrap();
+    assert_eq!(data.trim(), expected.trim());
+
+    // The checksum covers every byte before its own line
+    let checksum = format!("{:x}", Sha256::digest(format!("{}\n", data).as_bytes()));
+    assert_eq!(checksum_line, format!("#sha256:{}", checksum));
+}
+
+#[test]
+fn test_resume_from_snapshot() {
+    let state_file = temp_file("state.json");
+
+    // comprehensive.csv split in two halves, processed with a snapshot in between
+    run_success_test_with_args("comprehensive_part1", "comprehensive_part1", &["--save", &state_file]);
+    run_success_test_with_args("comprehensive_part2", "comprehensive", &["--resume", &state_file]);
+    fs::remove_file(&state_file).unwrap();
+}
+
+#[test]
+fn test_parallel_files() {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--parallel-files", "tests/data/disjoint_part1.csv", "tests/data/disjoint_part2.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let expected = fs::read_to_string("tests/expected/disjoint.expected")
+        .expect("Failed to read expected output file");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected.trim());
+
+    // Client 4 has transactions in both halves
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--parallel-files", "tests/data/comprehensive_part1.csv", "tests/data/comprehensive_part2.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("client 4 has transactions in more than one input file"));
+
+    // Seeded clients start from their seed balance in every file, like when processing the files in sequence
+    let args = ["--seed-balances", "tests/data/seed_balances_duplicate.csv", "--seed-duplicate-policy", "sum",
+                "tests/data/disjoint_part1.csv", "tests/data/disjoint_part2.csv"];
+    let sequential = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(args)
+        .output()
+        .expect("Failed to execute binary");
+    let parallel = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .arg("--parallel-files")
+        .args(args)
+        .output()
+        .expect("Failed to execute binary");
+    assert!(parallel.status.success(), "{}", String::from_utf8_lossy(&parallel.stderr));
+    assert_eq!(String::from_utf8_lossy(&parallel.stdout), String::from_utf8_lossy(&sequential.stdout));
+}
+
+#[test]
+fn test_output_precision() {
+    run_success_test_with_args("comprehensive", "comprehensive_precision_2", &["--output-precision", "2"]);
+}
+
+#[test]
+fn test_chronological_order() {
+    run_success_test("timestamps_shuffled");
+    run_success_test_with_args("timestamps_shuffled", "timestamps_shuffled_chronological", &["--chronological"]);
+}
+
+#[test]
+fn test_output_file() {
+    let output_file = temp_file("output.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--output", &output_file, "tests/data/comprehensive.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let actual = fs::read_to_string(&output_file)
+        .expect("Failed to read output file");
+    fs::remove_file(&output_file).unwrap();
+    let expected = fs::read_to_string("tests/expected/comprehensive.expected").unwrap();
+    assert_eq!(actual.trim(), expected.trim());
+}
+
+#[test]
+fn test_json_error_format() {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--error-format", "json", "tests/data/strict_withdrawal.csv"])
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success());
+
+    // Every line of stderr is a JSON object, the summary included
+    let lines: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Diagnostic is not a JSON line"))
+        .collect();
+    assert_eq!(lines, vec![
+        serde_json::json!({"tx": 3, "reason": "Insufficient funds"}),
+        serde_json::json!({"processed": 4, "applied": 3, "rejected": 1, "accounts": 2}),
+    ]);
+
+    // Including the other diagnostics, such as replayed transactions and the verbose ones
+    let input_file = temp_file("replay.csv");
+    fs::write(&input_file, "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\nresolve,1,1,\n\
+        deposit,1,1,5.0\ndispute,1,1,\nresolve,1,1,\nresolve,1,1,\nchargeback,1,1,\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--error-format", "json", "--quiet", "--verbose", &input_file])
+        .output()
+        .expect("Failed to execute binary");
+    fs::remove_file(&input_file).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr).lines().collect::<Vec<_>>(), vec![
+        r#"{"message":"Ignoring already processed transaction 1"}"#,
+        r#"{"message":"Transaction 1 was already resolved, ignoring resolve"}"#,
+        r#"{"message":"Transaction 1 was already resolved, ignoring chargeback"}"#,
+    ]);
+
+    // A dispute holding less than the disputed amount, as the funds were already withdrawn
+    let input_file = temp_file("partial_hold.csv");
+    fs::write(&input_file, "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,3.0\ndispute,1,1,\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["--error-format", "json", "--quiet", &input_file])
+        .output()
+        .expect("Failed to execute binary");
+    fs::remove_file(&input_file).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr).trim(),
+        r#"{"message":"Disputing transaction 1 with not enough balance available, holding amount 2 instead"}"#);
+}
+
+#[test]
+fn test_server() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["server", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute binary");
+
+    let mut listening = String::new();
+    BufReader::new(server.stdout.take().unwrap()).read_line(&mut listening).unwrap();
+    let address = listening.trim().strip_prefix("Listening on ").expect("Server address not printed");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,2.5\n\
+        deposit,3,x,1.0\n  DUMP  \n").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    // Another connection with different headers is rejected like schema drift between input files
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"client,type,tx,amount\n1,deposit,4,1.0\n").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut drift_response = String::new();
+    stream.read_to_string(&mut drift_response).unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let (error, balances) = response.split_once('\n').unwrap();
+    assert!(error.starts_with("Error: CSV error:"), "Unexpected response: {}", response);
+    assert_eq!(balances, "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,5,0,5,false\n");
+    assert_eq!(drift_response, "Error: Schema drift between input files: expected headers [type,client,tx,amount] \
+        but found [client,type,tx,amount]\n");
+}
+
+#[test]
+fn test_server_options() {
+    // The engine flags and config file apply to the server as to a batch run
+    let config_file = temp_file("server_config.toml");
+    fs::write(&config_file, "strict = true\n").unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["server", "--config", &config_file, "--bool-format", "numeric", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute binary");
+
+    let mut listening = String::new();
+    BufReader::new(server.stdout.take().unwrap()).read_line(&mut listening).unwrap();
+    let address = listening.trim().strip_prefix("Listening on ").expect("Server address not printed");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,20.0\nDUMP\n").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_file(&config_file).unwrap();
+
+    assert_eq!(response, "Error: Account error: Insufficient funds\nclient,available,held,total,locked\n1,10,0,10,0\n");
+
+    // The flags of the batch inputs and outputs are rejected
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["server", "--save", "state.json", "127.0.0.1:0"])
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage: "));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_interrupt() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(["server", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute binary");
+
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut listening = String::new();
+    stdout.read_line(&mut listening).unwrap();
+    let address = listening.trim().strip_prefix("Listening on ").expect("Server address not printed");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\nDUMP\n").unwrap();
+    // Wait for the deposit to be processed
+    let mut dump = String::new();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    while !dump.starts_with("1,") {
+        dump.clear();
+        reader.read_line(&mut dump).unwrap();
+    }
+
+    // SAFETY: kill only sends a signal to the server process
+    unsafe {
+        libc::kill(server.id() as libc::pid_t, libc::SIGINT);
+    }
+    let mut balances = String::new();
+    stdout.read_to_string(&mut balances).unwrap();
+    assert!(server.wait().unwrap().success());
+    assert_eq!(balances, "client,available,held,total,locked\n1,10,0,10,false\n");
+}
+
+fn temp_file(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("transactions_engine_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
 fn run_success_test(test_name: &str) {
+    run_success_test_with_args(test_name, test_name, &[]);
+}
+
+fn run_success_test_with_args(test_name: &str, expected_name: &str, args: &[&str]) {
     // Get input and expected files
     let input_file = format!("tests/data/{}.csv", test_name);
-    let expected_file = format!("tests/expected/{}.expected", test_name);
+    let expected_file = format!("tests/expected/{}.expected", expected_name);
     
     assert!(Path::new(&input_file).exists(), "Input file not found: {}", input_file);
     assert!(Path::new(&expected_file).exists(), "Expected file not found: {}", expected_file);
     
     // Run the binary with the test input
     let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(args)
         .arg(&input_file)
         .output()
         .expect("Failed to execute binary");
@@ -76,12 +632,17 @@ fn run_success_test(test_name: &str) {
 }
 
 fn run_error_test(test_name: &str) {
+    run_error_test_with_args(test_name, &[]);
+}
+
+fn run_error_test_with_args(test_name: &str, args: &[&str]) {
     let input_file = format!("tests/data/{}.csv", test_name);
 
     assert!(Path::new(&input_file).exists(), "Input file not found: {}", input_file);
 
     // Run the binary with the test input
     let output = Command::new(env!("CARGO_BIN_EXE_transactions_engine"))
+        .args(args)
         .arg(&input_file)
         .output()
         .expect("Failed to execute binary");